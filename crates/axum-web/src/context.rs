@@ -10,12 +10,88 @@ use uuid::Uuid;
 
 pub use structured_logger::unix_ms;
 
+/// TraceContext is the W3C trace context of a request, see https://www.w3.org/TR/trace-context/.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,  // 32 lowercase hex digits
+    pub span_id: String,   // 16 lowercase hex digits, the span of the current context
+    pub parent_id: String, // 16 lowercase hex digits, empty for a root span
+    pub flags: u8,
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceContext {
+    /// Starts a new sampled trace.
+    pub fn new() -> Self {
+        Self {
+            trace_id: Uuid::new_v4().simple().to_string(),
+            span_id: new_span_id(),
+            parent_id: "".to_string(),
+            flags: 1,
+        }
+    }
+
+    /// Continues the trace from a traceparent header value with a new span.
+    pub fn from_traceparent(val: &str) -> Option<Self> {
+        let parts: Vec<&str> = val.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+            return None;
+        }
+        // future versions may append fields
+        if parts[0] == "00" && parts.len() != 4 {
+            return None;
+        }
+        if !is_hex_id(parts[1], 32) || !is_hex_id(parts[2], 16) || !is_hex_id(parts[3], 2) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: parts[1].to_string(),
+            span_id: new_span_id(),
+            parent_id: parts[2].to_string(),
+            flags: u8::from_str_radix(parts[3], 16).ok()?,
+        })
+    }
+
+    /// Returns a child span of the current span.
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            parent_id: self.span_id.clone(),
+            flags: self.flags,
+        }
+    }
+
+    /// Returns the traceparent header value that makes the current span the parent.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+fn new_span_id() -> String {
+    let id = Uuid::new_v4().simple().to_string();
+    id[..16].to_string()
+}
+
+fn is_hex_id(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && (len == 2 || s.bytes().any(|b| b != b'0'))
+}
+
 pub struct ReqContext {
     pub rid: String,   // from x-request-id header
     pub user: xid::Id, // from x-auth-user header
     pub rating: i8,    // from x-auth-user-rating header, 0 if not present
     pub unix_ms: u64,
     pub start: Instant,
+    pub trace: TraceContext, // from traceparent header, a new trace if not present
     pub kv: RwLock<BTreeMap<String, Value>>,
 }
 
@@ -27,10 +103,21 @@ impl ReqContext {
             rating,
             unix_ms: unix_ms(),
             start: Instant::now(),
+            trace: TraceContext::new(),
             kv: RwLock::new(BTreeMap::new()),
         }
     }
 
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Returns a context for a sub operation with a child span of the current span.
+    pub fn child(&self) -> Self {
+        Self::new(&self.rid, self.user, self.rating).with_trace(self.trace.child())
+    }
+
     pub async fn set(&self, key: &str, value: Value) {
         let mut kv = self.kv.write().await;
         kv.insert(key.to_string(), value);
@@ -54,8 +141,14 @@ pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
     let rating = i8::from_str(&rating).unwrap_or(0);
 
     let uid = xid::Id::from_str(&user).unwrap_or_default();
+    let trace = req
+        .headers()
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(TraceContext::from_traceparent)
+        .unwrap_or_default();
 
-    let ctx = Arc::new(ReqContext::new(&rid, uid, rating));
+    let ctx = Arc::new(ReqContext::new(&rid, uid, rating).with_trace(trace));
     req.extensions_mut().insert(ctx.clone());
 
     let res = next.run(req).await;
@@ -72,6 +165,9 @@ pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
         method = method,
         uri = uri,
         rid = rid,
+        trace = &ctx.trace.trace_id,
+        span = &ctx.trace.span_id,
+        parent = &ctx.trace.parent_id,
         user = user,
         app = app,
         rating = rating,
@@ -96,3 +192,49 @@ pub fn extract_header(hm: &HeaderMap, key: &str, or: impl FnOnce() -> String) ->
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_context() {
+        let tc = TraceContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        assert_eq!(tc.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(tc.parent_id, "00f067aa0ba902b7");
+        assert_eq!(tc.span_id.len(), 16);
+        assert_ne!(tc.span_id, tc.parent_id);
+        assert_eq!(tc.flags, 1);
+        assert_eq!(
+            tc.traceparent(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", tc.span_id)
+        );
+
+        let child = tc.child();
+        assert_eq!(child.trace_id, tc.trace_id);
+        assert_eq!(child.parent_id, tc.span_id);
+
+        for val in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xx",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert!(TraceContext::from_traceparent(val).is_none(), "{}", val);
+        }
+        assert!(TraceContext::from_traceparent(
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-xx"
+        )
+        .is_some());
+
+        let tc = TraceContext::new();
+        assert_eq!(tc.trace_id.len(), 32);
+        assert!(tc.parent_id.is_empty());
+    }
+}
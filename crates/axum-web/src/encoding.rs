@@ -1,11 +1,13 @@
 use axum::http::header;
 use libflate::gzip::{Decoder, Encoder};
+use serde::Deserialize;
 use std::{io, string::ToString};
 
 // recommended minimum size for compression.
 pub const MIN_ENCODING_SIZE: u16 = 128;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Encoding {
     Zstd,
     Gzip,
@@ -569,7 +569,7 @@ where
     }
 }
 
-fn get_content_type(headers: &HeaderMap) -> Result<PackObject<()>, String> {
+pub fn get_content_type(headers: &HeaderMap) -> Result<PackObject<()>, String> {
     let content_type = if let Some(content_type) = headers.get(header::CONTENT_TYPE) {
         content_type
     } else {
@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use axum_web::{
+    context::{unix_ms, ReqContext},
+    object::{cbor_to_vec, PackObject},
+};
+
+use crate::conf;
+
+// Entry records a mutation of production data by the jobs.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    pub ts: u64, // unix milliseconds
+    pub rid: String,
+    pub action: String,
+    pub tid: String,
+    pub gid: String,
+    pub cid: String,
+    pub language: String,
+    pub version: i16,
+    pub old_status: Option<i8>,
+    pub new_status: Option<i8>,
+    pub reason: String,
+}
+
+impl Entry {
+    pub fn new(ctx: &ReqContext, tid: &PackObject<xid::Id>) -> Self {
+        Self {
+            rid: ctx.rid.clone(),
+            tid: tid.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn publication(
+        mut self,
+        gid: &PackObject<xid::Id>,
+        cid: &PackObject<xid::Id>,
+        language: &str,
+        version: i16,
+    ) -> Self {
+        self.gid = gid.to_string();
+        self.cid = cid.to_string();
+        self.language = language.to_string();
+        self.version = version;
+        self
+    }
+
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.reason = reason.to_string();
+        self
+    }
+
+    // returns a copy of the entry for the action that changed the status.
+    pub fn action(&self, action: &str, old_status: Option<i8>, new_status: Option<i8>) -> Self {
+        let mut entry = self.clone();
+        entry.ts = unix_ms();
+        entry.action = action.to_string();
+        entry.old_status = old_status;
+        entry.new_status = new_status;
+        entry
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Query {
+    pub gid: Option<String>,
+    pub cid: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.gid.as_ref().map_or(true, |gid| gid == &entry.gid)
+            && self.cid.as_ref().map_or(true, |cid| cid == &entry.cid)
+    }
+}
+
+// Sink is where the audit entries are appended to.
+pub trait Sink: Send + Sync {
+    fn append(&self, entry: &Entry) -> anyhow::Result<()>;
+
+    // returns the latest entries that match the query, newest first.
+    fn query(&self, query: &Query) -> anyhow::Result<Vec<Entry>>;
+}
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+// AuditLog is the append-only audit trail, it does nothing if no sink is configured.
+pub struct AuditLog {
+    sink: Option<Box<dyn Sink>>,
+}
+
+impl AuditLog {
+    pub fn new(cfg: &conf::Audit) -> anyhow::Result<Self> {
+        if cfg.path.is_empty() {
+            return Ok(Self { sink: None });
+        }
+        let sink = FileSink::new(&cfg.path, cfg.format)?;
+        Ok(Self {
+            sink: Some(Box::new(sink)),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    // appends the entry, a failure is logged since the mutation has been done.
+    pub fn append(&self, entry: Entry) {
+        if let Some(sink) = &self.sink {
+            if let Err(err) = sink.append(&entry) {
+                log::error!(target: "job",
+                    action = "audit",
+                    rid = &entry.rid,
+                    entry = log::as_serde!(entry),
+                    error = err.to_string();
+                    "append failed",
+                );
+            }
+        }
+    }
+
+    pub fn query(&self, query: &Query) -> anyhow::Result<Vec<Entry>> {
+        match &self.sink {
+            None => anyhow::bail!("audit log is not configured"),
+            Some(sink) => sink.query(query),
+        }
+    }
+}
+
+// FileSink appends JSON lines or CBOR values to a file.
+pub struct FileSink {
+    path: PathBuf,
+    format: conf::Format,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn new(path: &str, format: conf::Format) -> anyhow::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            format,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn append(&self, entry: &Entry) -> anyhow::Result<()> {
+        let data = match self.format {
+            conf::Format::Json => {
+                let mut data = serde_json::to_vec(entry)?;
+                data.push(b'\n');
+                data
+            }
+            conf::Format::Cbor => cbor_to_vec(entry)?,
+        };
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&data)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn query(&self, query: &Query) -> anyhow::Result<Vec<Entry>> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .clamp(1, MAX_QUERY_LIMIT);
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut entries: VecDeque<Entry> = VecDeque::with_capacity(limit);
+        let mut keep = |entry: Entry| {
+            if query.matches(&entry) {
+                if entries.len() == limit {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+        };
+
+        match self.format {
+            conf::Format::Json => {
+                for line in reader.lines() {
+                    let line = line?;
+                    if !line.is_empty() {
+                        keep(serde_json::from_str(&line)?);
+                    }
+                }
+            }
+            conf::Format::Cbor => {
+                while !reader.fill_buf()?.is_empty() {
+                    let entry: Entry = ciborium::from_reader(&mut reader)
+                        .map_err(|err| anyhow::anyhow!("invalid CBOR entry, {}", err))?;
+                    keep(entry);
+                }
+            }
+        }
+
+        Ok(entries.into_iter().rev().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_sink() {
+        let dir = std::env::temp_dir().join(format!("rpa-audit-{}", uuid::Uuid::new_v4()));
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let gids = [xid::new(), xid::new()];
+        for format in [conf::Format::Json, conf::Format::Cbor] {
+            let path = dir.join(format!("audit.{:?}", format));
+            let audit = AuditLog::new(&conf::Audit {
+                path: path.to_str().unwrap().to_string(),
+                format,
+            })
+            .unwrap();
+
+            for i in 0..4 {
+                let entry = Entry::new(&ctx, &PackObject::Cbor(xid::new()))
+                    .publication(
+                        &PackObject::Cbor(gids[i % 2]),
+                        &PackObject::Cbor(xid::new()),
+                        "eng",
+                        1,
+                    )
+                    .reason("approved");
+                audit.append(entry.action("set_publication_status", Some(0), Some(i as i8)));
+            }
+
+            let entries = audit
+                .query(&Query {
+                    gid: Some(gids[0].to_string()),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].new_status, Some(2));
+            assert_eq!(entries[1].new_status, Some(0));
+            assert_eq!(entries[0].rid, "rid");
+            assert_eq!(entries[0].action, "set_publication_status");
+
+            let entries = audit
+                .query(&Query {
+                    cid: Some(entries[1].cid.clone()),
+                    limit: Some(1),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].gid, gids[0].to_string());
+
+            let entries = audit
+                .query(&Query {
+                    limit: Some(3),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(entries.len(), 3);
+            assert_eq!(entries[0].new_status, Some(3));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
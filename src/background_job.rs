@@ -11,67 +11,214 @@ use apalis_core::{
 };
 use apalis_cron::{CronStream, Schedule};
 use chrono::{DateTime, Utc};
-use std::{str::FromStr, sync::Arc, time::Instant};
+use std::{collections::BTreeMap, str::FromStr, sync::Arc, time::Instant};
 use tower::ServiceBuilder;
 
 use crate::conf;
-use crate::jobs;
+use crate::jobs::{
+    self,
+    cancel::Cancelled,
+    registry::{RunRecord, RunStatus},
+    DIGEST_JOB, OUTBOX_JOB, PUBLISH_JOB, REAPER_JOB, REVIEW_JOB, TRANSLATION_JOB,
+};
+
+// the jobs and their cron schedules, every job runs on its own worker.
+const SCHEDULES: &[(&str, &str)] = &[
+    (REVIEW_JOB, "0 * * * * * *"),
+    (PUBLISH_JOB, "0 * * * * * *"),
+    (REAPER_JOB, "0 0 * * * * *"),
+    (TRANSLATION_JOB, "30 * * * * * *"),
+    (DIGEST_JOB, "0 30 * * * * *"),
+    (OUTBOX_JOB, "*/10 * * * * * *"),
+];
 
 #[derive(Default, Debug, Clone)]
-struct Reminder(DateTime<Utc>);
-impl From<DateTime<Utc>> for Reminder {
+struct Tick(DateTime<Utc>);
+impl From<DateTime<Utc>> for Tick {
     fn from(t: DateTime<Utc>) -> Self {
-        Reminder(t)
+        Tick(t)
     }
 }
 
-impl Job for Reminder {
-    const NAME: &'static str = "reminder::MinutelyReminder";
+impl Job for Tick {
+    const NAME: &'static str = "cron::Tick";
 }
 
-async fn send_reminder(_job: Reminder, mut ctx: JobContext) {
+// the job that a worker runs on every tick.
+#[derive(Clone)]
+struct JobName(&'static str);
+
+async fn send_tick(_job: Tick, ctx: JobContext) {
+    let job = ctx.data_opt::<JobName>().unwrap().0;
+    run(job, ctx).await
+}
+
+// runs the job unless it is paused, this replica is not the leader or the previous
+// run is still running, the run is recorded in the job registry.
+async fn run(job: &'static str, mut ctx: JobContext) {
     let start = Instant::now();
-    let state: &Arc<conf::AppState> = ctx.data_opt().unwrap();
-    let rpa: &Arc<jobs::RPA> = ctx.data_opt().unwrap();
+    let state = ctx.data_opt::<Arc<conf::AppState>>().unwrap().clone();
+    let rpa = ctx.data_opt::<Arc<jobs::RPA>>().unwrap().clone();
     let mark = state.handling.clone();
     let rid = uuid::Uuid::from_u128(ctx.id().inner().0).to_string();
-    match rpa.execute(&ctx, state.clone()).await {
-        Ok(_) => {
+    let run_at = ctx.run_at().timestamp_millis();
+
+    let begun = if state.jobs.is_paused(job) {
+        Err("paused".to_string())
+    } else if !state.leader.is_leader() {
+        Err(format!("not the leader, {}", state.leader.holder))
+    } else {
+        state
+            .jobs
+            .begin(job, rpa.run_timeout())
+            .ok_or_else(|| "the previous run is still running".to_string())
+    };
+    let cancel = match begun {
+        Ok(cancel) => cancel,
+        Err(reason) => {
+            ctx.set_status(JobState::Done);
+            log::info!(target: "job",
+                action = "execute",
+                rid = &rid,
+                job = job;
+                "skipped, {}", reason,
+            );
+            state.jobs.record(
+                job,
+                RunRecord {
+                    rid,
+                    start: run_at as u64,
+                    elapsed: 0,
+                    status: RunStatus::Skipped,
+                    message: reason,
+                    counts: BTreeMap::new(),
+                },
+            );
+            return;
+        }
+    };
+    let res = rpa.execute(&ctx, job, cancel.clone()).await;
+    state.jobs.end(job, &cancel);
+    let record = match res {
+        Ok(counts) => {
             ctx.set_status(JobState::Done);
             log::info!(target: "job",
                 action = "execute",
                 rid = &rid,
-                start = ctx.run_at().timestamp_millis(),
-                elapsed = start.elapsed().as_millis() as u64;
+                job = job,
+                start = run_at,
+                elapsed = start.elapsed().as_millis() as u64,
+                counts = log::as_serde!(counts);
                 "finished",
             );
+            (RunStatus::Done, "".to_string(), counts)
+        }
+        Err(err) if err.downcast_ref::<Cancelled>().is_some() => {
+            ctx.set_status(JobState::Killed);
+            log::warn!(target: "job",
+                action = "execute",
+                rid = &rid,
+                job = job,
+                start = run_at,
+                elapsed = start.elapsed().as_millis() as u64,
+                error = err.to_string();
+                "stopped",
+            );
+            (RunStatus::Cancelled, err.to_string(), BTreeMap::new())
         }
         Err(err) => {
             ctx.set_status(JobState::Failed);
             log::error!(target: "job",
                 action = "execute",
                 rid = &rid,
-                start = ctx.run_at().timestamp_millis(),
+                job = job,
+                start = run_at,
                 elapsed = start.elapsed().as_millis() as u64,
                 error = err.to_string();
                 "failed",
             );
+            (RunStatus::Failed, err.to_string(), BTreeMap::new())
         }
-    }
+    };
+    state.jobs.record(
+        job,
+        RunRecord {
+            rid,
+            start: run_at as u64,
+            elapsed: start.elapsed().as_millis() as u64,
+            status: record.0,
+            message: record.1,
+            counts: record.2,
+        },
+    );
 
     let _ = mark.as_str(); // avoid unused warning
 }
 
-pub fn new(state: Arc<conf::AppState>, cfg: conf::Conf) -> Monitor<TokioExecutor> {
-    let rpa = Arc::new(jobs::RPA::new(cfg));
-    let schedule = Schedule::from_str("0 * * * * * *").unwrap();
-    let service = ServiceBuilder::new()
-        .layer(Extension(state))
-        .layer(Extension(rpa))
-        .service(job_fn(send_reminder));
-    let worker = WorkerBuilder::new(conf::APP_NAME)
-        .stream(CronStream::new(schedule).timer(TokioTimer).to_stream())
-        .build(service);
+// handles the notifications pushed by the webhook and the delayed items as they
+// come, they are left for the cron run if the job would skip them.
+fn spawn_inbox(state: Arc<conf::AppState>, rpa: Arc<jobs::RPA>) {
+    let mut rx = match state.inbox.take() {
+        Some(rx) => rx,
+        None => return,
+    };
+    // delayed items go to the inbox when they are due.
+    let delayed = state.clone();
+    let queue = rpa.delayed.clone();
+    tokio::spawn(async move {
+        loop {
+            let item = queue.next().await;
+            let tid = item.tid.to_string();
+            if !delayed.inbox.push(item) {
+                log::info!(target: "job",
+                    action = "delay",
+                    tid = &tid,
+                    job = REVIEW_JOB;
+                    "due, left for the cron run",
+                );
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(item) = rx.recv().await {
+            let tid = item.tid.to_string();
+            if state.jobs.is_paused(REVIEW_JOB) || !state.leader.is_leader() {
+                log::info!(target: "job",
+                    action = "handle_notification",
+                    tid = &tid,
+                    job = REVIEW_JOB;
+                    "skipped, left for the cron run",
+                );
+            } else {
+                rpa.handle_notification(item).await;
+            }
+            state.inbox.release(&tid);
+        }
+    });
+}
 
-    Monitor::new().register(worker)
+pub fn new(state: Arc<conf::AppState>, cfg: conf::Conf) -> Monitor<TokioExecutor> {
+    let rpa = Arc::new(jobs::RPA::new(cfg, &state));
+    for (job, schedule) in SCHEDULES {
+        state.jobs.register(job, schedule);
+    }
+    state.jobs.set_delay_queue(REVIEW_JOB, rpa.delayed.clone());
+    spawn_inbox(state.clone(), rpa.clone());
+    SCHEDULES
+        .iter()
+        .fold(Monitor::new(), |monitor, &(job, schedule)| {
+            let service = ServiceBuilder::new()
+                .layer(Extension(state.clone()))
+                .layer(Extension(rpa.clone()))
+                .layer(Extension(JobName(job)))
+                .service(job_fn(send_tick));
+            let worker = WorkerBuilder::new(job)
+                .stream(
+                    CronStream::new(Schedule::from_str(schedule).unwrap())
+                        .timer(TokioTimer)
+                        .to_stream(),
+                )
+                .build(service);
+            monitor.register(worker)
+        })
 }
@@ -1,6 +1,21 @@
+use axum_web::{encoding::Encoding, object::PackObject};
 use config::{Config, ConfigError, File, FileFormat};
 use serde::Deserialize;
-use std::sync::Arc;
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
+
+use crate::audit::AuditLog;
+use crate::jobs::{
+    breaker::Breakers,
+    cancel::Cancel,
+    inbox::Inbox,
+    lease::Leader,
+    moderation::ListKind,
+    registry::Registry,
+    review::{Action, Rule},
+};
+use crate::logger::Levels;
+use crate::storage;
+use crate::telemetry::Tracer;
 
 pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -8,11 +23,44 @@ pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 #[derive(Clone)]
 pub struct AppState {
     pub handling: Arc<String>,
+    pub breakers: Arc<Breakers>,
+    pub tracer: Arc<Tracer>,
+    pub log_levels: Arc<Levels>,
+    pub admins: Arc<Vec<xid::Id>>,
+    pub notifiers: Arc<Vec<xid::Id>>,
+    pub audit: Arc<AuditLog>,
+    pub storage: Arc<dyn storage::Storage>,
+    pub leader: Arc<Leader>,
+    pub jobs: Arc<Registry>,
+    pub inbox: Arc<Inbox>,
+    pub shutdown: Cancel,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Log {
     pub level: String,
+    #[serde(default)]
+    pub targets: BTreeMap<String, String>, // level per target, e.g. "api", "job"
+    #[serde(default)]
+    pub files: Vec<LogFile>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogFile {
+    pub targets: String, // comma separated targets written to the file
+    pub path: String,
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64, // bytes, the file is rotated when it grows beyond
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize, // rotated files kept
+}
+
+fn default_log_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_log_max_files() -> usize {
+    5
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -21,6 +69,10 @@ pub struct Server {
     pub cert_file: String,
     pub key_file: String,
     pub graceful_shutdown: usize,
+    #[serde(default)]
+    pub admins: Vec<String>, // users allowed to call the admin API
+    #[serde(default)]
+    pub notifiers: Vec<String>, // users allowed to push notifications
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -29,12 +81,344 @@ pub struct Base {
     pub writing: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Review {
+    pub grace_period: u64,  // seconds
+    pub max_failures: u32,  // attempts before a failing task is removed
+    pub max_delayed: usize, // items waiting for the grace period
+    pub reviewers: Vec<String>,
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Review {
+    fn default() -> Self {
+        Self {
+            grace_period: 8 * 60,
+            max_failures: 3,
+            max_delayed: 1000,
+            reviewers: vec![],
+            rules: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Moderation {
+    pub timeout: u64, // seconds
+    pub lists: Vec<ModerationList>,
+    pub services: Vec<ModerationService>,
+}
+
+impl Default for Moderation {
+    fn default() -> Self {
+        Self {
+            timeout: 10,
+            lists: vec![],
+            services: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModerationList {
+    pub name: String,
+    pub path: String,
+    pub kind: ListKind,
+    pub action: Action,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModerationService {
+    pub name: String,
+    pub endpoint: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Cbor,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Upstream {
+    pub rate_limit: f64, // requests per second, 0 means no limit
+    pub burst: u32,
+    pub max_retries: u32,                  // retries on 429 Too Many Requests
+    pub encoding: Encoding,                // compression of request bodies
+    pub format: Format,                    // wire format of request bodies
+    pub batch_size: usize,                 // items in a batch call, 1 disables batch calls
+    pub connect_timeout: u64,              // seconds
+    pub timeout: u64,                      // seconds, of a whole request
+    pub pool_idle_timeout: u64,            // seconds an idle connection is kept
+    pub pool_max_idle: usize,              // idle connections kept per host
+    pub http2_prior_knowledge: bool,       // HTTP/2 without negotiation, e.g. h2c
+    pub http2_keep_alive_interval: u64,    // seconds
+    pub http2_keep_alive_timeout: u64,     // seconds
+    pub proxy: String,                     // proxy URL, from the environment if empty
+    pub headers: BTreeMap<String, String>, // extra default headers
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            rate_limit: 0.0,
+            burst: 1,
+            max_retries: 3,
+            encoding: Encoding::Gzip,
+            format: Format::Cbor,
+            batch_size: 100,
+            connect_timeout: 5,
+            timeout: 60,
+            pool_idle_timeout: 90,
+            pool_max_idle: 32,
+            http2_prior_knowledge: false,
+            http2_keep_alive_interval: 25,
+            http2_keep_alive_timeout: 15,
+            proxy: "".to_string(),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Breaker {
+    pub failure_threshold: u32,
+    pub open_timeout: u64, // seconds
+    pub half_open_requests: u32,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_timeout: 30,
+            half_open_requests: 1,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Otlp {
+    pub endpoint: String, // collector base URL, empty disables the export
+    pub service_name: String,
+    pub timeout: u64,        // seconds
+    pub flush_interval: u64, // seconds
+    pub batch_size: usize,
+    pub max_queue: usize, // spans beyond are dropped
+}
+
+impl Default for Otlp {
+    fn default() -> Self {
+        Self {
+            endpoint: "".to_string(),
+            service_name: APP_NAME.to_string(),
+            timeout: 10,
+            flush_interval: 5,
+            batch_size: 512,
+            max_queue: 2048,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Audit {
+    pub path: String, // empty disables the audit log
+    pub format: Format,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            path: "".to_string(),
+            format: Format::Json,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Storage {
+    pub path: String, // empty keeps the data in memory
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            path: "".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Lease {
+    pub kind: String, // "file", "http" or empty to run the scheduled jobs on every replica
+    pub name: String,
+    pub ttl: u64,         // seconds
+    pub path: String,     // for "file"
+    pub endpoint: String, // for "http"
+}
+
+impl Default for Lease {
+    fn default() -> Self {
+        Self {
+            kind: "".to_string(),
+            name: APP_NAME.to_string(),
+            ttl: 30,
+            path: "".to_string(),
+            endpoint: "".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Shard {
+    pub index: Option<u32>, // from the hostname ordinal if not set
+    pub count: u32,
+}
+
+impl Default for Shard {
+    fn default() -> Self {
+        Self {
+            index: None,
+            count: 1,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Reaper {
+    pub max_age: u64,        // seconds, 0 disables the job
+    pub statuses: Vec<i8>,   // statuses of the notifications to reap
+    pub escalate_to: String, // user id, stale tasks are acked as expired if empty
+}
+
+impl Default for Reaper {
+    fn default() -> Self {
+        Self {
+            max_age: 0,
+            statuses: vec![0],
+            escalate_to: "".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Translation {
+    pub languages: Vec<PackObject<isolang::Language>>, // target languages
+    pub translators: Vec<String>,                      // user ids
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Digest {
+    pub notifier: String, // "file", "webhook" or empty to disable the job
+    pub path: String,     // for "file"
+    pub endpoint: String, // for "webhook"
+    pub subject: String,  // templates, see config/default.toml
+    pub body: String,
+    pub item: String,
+    pub link: String,
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Self {
+            notifier: "".to_string(),
+            path: "".to_string(),
+            endpoint: "".to_string(),
+            subject: "{count} publications need your attention".to_string(),
+            body: "Hi {reviewer},\n\nThe publications below were escalated or failed since the last digest.\n\n{items}".to_string(),
+            item: "- {kind}: {gid}/{cid} {language} v{version}, {reason}\n  {link}\n".to_string(),
+            link: "".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Webhook {
+    pub endpoint: String, // empty disables the webhook
+    pub secret: String,   // key of the HMAC-SHA256 signature, empty sends unsigned requests
+    pub format: Format,
+    pub max_retries: u32,    // retries before an event is dropped
+    pub retry_interval: u64, // seconds, doubled on every retry
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Self {
+            endpoint: "".to_string(),
+            secret: "".to_string(),
+            format: Format::Json,
+            max_retries: 10,
+            retry_interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Jobs {
+    pub persist_paused: bool, // keep paused jobs paused across restarts
+    pub run_timeout: u64,     // seconds, 0 means no deadline
+    pub item_timeout: u64,    // seconds, 0 means no deadline
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            persist_paused: false,
+            run_timeout: 600,
+            item_timeout: 30,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Conf {
     pub env: String,
     pub log: Log,
     pub server: Server,
     pub base: Base,
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, Upstream>,
+    #[serde(default)]
+    pub breaker: Breaker,
+    #[serde(default)]
+    pub review: Review,
+    #[serde(default)]
+    pub moderation: Moderation,
+    #[serde(default)]
+    pub otlp: Otlp,
+    #[serde(default)]
+    pub audit: Audit,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub lease: Lease,
+    #[serde(default)]
+    pub shard: Shard,
+    #[serde(default)]
+    pub jobs: Jobs,
+    #[serde(default)]
+    pub reaper: Reaper,
+    #[serde(default)]
+    pub translation: Translation,
+    #[serde(default)]
+    pub digest: Digest,
+    #[serde(default)]
+    pub webhook: Webhook,
 }
 
 impl Conf {
@@ -49,9 +433,189 @@ impl Conf {
         builder.build()?.try_deserialize::<Conf>()
     }
 
-    pub async fn new_app_state(&self) -> anyhow::Result<Arc<AppState>> {
+    pub async fn new_app_state(&self, log_levels: Arc<Levels>) -> anyhow::Result<Arc<AppState>> {
+        let users = |uids: &[String], kind: &str| -> anyhow::Result<Vec<xid::Id>> {
+            let mut users = Vec::with_capacity(uids.len());
+            for uid in uids {
+                users.push(
+                    xid::Id::from_str(uid)
+                        .map_err(|err| anyhow::anyhow!("invalid {} {:?}, {}", kind, uid, err))?,
+                );
+            }
+            Ok(users)
+        };
+        let admins = users(&self.server.admins, "admin")?;
+        let notifiers = users(&self.server.notifiers, "notifier")?;
+
+        let storage = storage::new_storage(&self.storage)?;
         Ok(Arc::new(AppState {
             handling: Arc::new("handling".to_string()),
+            breakers: Arc::new(Breakers::new(&self.breaker)),
+            tracer: Arc::new(Tracer::new(&self.otlp)?),
+            log_levels,
+            admins: Arc::new(admins),
+            notifiers: Arc::new(notifiers),
+            audit: Arc::new(AuditLog::new(&self.audit)?),
+            jobs: Arc::new(Registry::new(storage.clone(), self.jobs.persist_paused)),
+            storage,
+            leader: Arc::new(Leader::new(&self.lease)?),
+            inbox: Arc::new(Inbox::default()),
+            shutdown: Cancel::default(),
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    // keys of the tables in config/default.toml, serde drops unknown keys silently.
+    const KEYS: &[(&str, &[&str])] = &[
+        (
+            "",
+            &[
+                "env",
+                "log",
+                "server",
+                "base",
+                "upstreams",
+                "breaker",
+                "review",
+                "moderation",
+                "otlp",
+                "audit",
+                "storage",
+                "lease",
+                "shard",
+                "jobs",
+                "reaper",
+                "translation",
+                "digest",
+                "webhook",
+            ],
+        ),
+        ("log", &["level", "targets", "files"]),
+        (
+            "server",
+            &[
+                "port",
+                "cert_file",
+                "key_file",
+                "graceful_shutdown",
+                "admins",
+                "notifiers",
+            ],
+        ),
+        ("base", &["taskbase", "writing"]),
+        (
+            "breaker",
+            &["failure_threshold", "open_timeout", "half_open_requests"],
+        ),
+        (
+            "review",
+            &[
+                "grace_period",
+                "max_failures",
+                "max_delayed",
+                "reviewers",
+                "rules",
+            ],
+        ),
+        ("moderation", &["timeout", "lists", "services"]),
+        (
+            "otlp",
+            &[
+                "endpoint",
+                "service_name",
+                "timeout",
+                "flush_interval",
+                "batch_size",
+                "max_queue",
+            ],
+        ),
+        ("audit", &["path", "format"]),
+        ("storage", &["path"]),
+        ("lease", &["kind", "name", "ttl", "path", "endpoint"]),
+        ("shard", &["index", "count"]),
+        ("jobs", &["persist_paused", "run_timeout", "item_timeout"]),
+        ("reaper", &["max_age", "statuses", "escalate_to"]),
+        ("translation", &["languages", "translators"]),
+        (
+            "digest",
+            &[
+                "notifier", "path", "endpoint", "subject", "body", "item", "link",
+            ],
+        ),
+        (
+            "webhook",
+            &[
+                "endpoint",
+                "secret",
+                "format",
+                "max_retries",
+                "retry_interval",
+            ],
+        ),
+    ];
+    const UPSTREAM_KEYS: &[&str] = &[
+        "rate_limit",
+        "burst",
+        "max_retries",
+        "encoding",
+        "format",
+        "batch_size",
+        "connect_timeout",
+        "timeout",
+        "pool_idle_timeout",
+        "pool_max_idle",
+        "http2_prior_knowledge",
+        "http2_keep_alive_interval",
+        "http2_keep_alive_timeout",
+        "proxy",
+        "headers",
+    ];
+    const RULE_KEYS: &[&str] = &["kind", "min", "max", "allow", "action"];
+
+    fn check_keys(name: &str, table: &Value, keys: &[&str]) {
+        for key in table.as_object().unwrap().keys() {
+            assert!(
+                keys.contains(&key.as_str()),
+                "unknown key {:?} in [{}]",
+                key,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn default_config() {
+        let file = File::new("./config/default.toml", FileFormat::Toml);
+        let value: Value = Config::builder()
+            .add_source(file)
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        for (name, keys) in KEYS {
+            let table = if name.is_empty() {
+                &value
+            } else {
+                &value[name]
+            };
+            check_keys(name, table, keys);
+        }
+        for (name, upstream) in value["upstreams"].as_object().unwrap() {
+            check_keys(&format!("upstreams.{}", name), upstream, UPSTREAM_KEYS);
+        }
+        for rule in value["review"]["rules"].as_array().unwrap() {
+            check_keys("review.rules", rule, RULE_KEYS);
+        }
+
+        let cfg = Conf::from("./config/default.toml").unwrap();
+        assert_eq!(cfg.review.grace_period, 480);
+        assert_eq!(cfg.review.rules.len(), 1);
+        assert!(cfg.moderation.services.is_empty());
+        assert_eq!(cfg.upstreams.len(), 2);
+    }
+}
@@ -1,15 +1,34 @@
-use axum::{extract::State, middleware, routing, Json, Router};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing, Extension, Json, Router,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 use tower::ServiceBuilder;
 use tower_http::{
     catch_panic::CatchPanicLayer,
     compression::{predicate::SizeAbove, CompressionLayer},
 };
 
-use axum_web::{context, encoding};
+use axum_web::{
+    context::{self, ReqContext},
+    encoding,
+    erring::{valid_user, HTTPError, SuccessResponse},
+    object::{cbor_from_slice, PackObject},
+};
 
-use crate::conf;
+use crate::{
+    audit, conf,
+    jobs::{
+        breaker::CircuitState,
+        registry::{JobInfo, NotFound},
+        NotificationOutput, PublicationInput, PUBLISH_KIND,
+    },
+    telemetry,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct AppVersion {
@@ -24,15 +43,278 @@ pub async fn version(State(_): State<Arc<conf::AppState>>) -> Json<AppVersion> {
     })
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct Readiness {
+    pub name: String,
+    pub version: String,
+    pub upstreams: Vec<CircuitState>,
+    pub leader: bool, // whether the replica runs the scheduled jobs
+    pub holder: String,
+}
+
+// readyz reports the circuit state of upstreams and the leadership. It does not
+// fail when a circuit is open or the replica is not the leader, the HTTP API keeps
+// working while the jobs wait for the upstream or run on another replica.
+pub async fn readyz(State(app): State<Arc<conf::AppState>>) -> Json<Readiness> {
+    Json(Readiness {
+        name: conf::APP_NAME.to_string(),
+        version: conf::APP_VERSION.to_string(),
+        upstreams: app.breakers.states(),
+        leader: app.leader.is_leader(),
+        holder: app.leader.holder.clone(),
+    })
+}
+
+// metrics in the Prometheus text format.
+pub async fn metrics(State(app): State<Arc<conf::AppState>>) -> impl IntoResponse {
+    let states = app.breakers.states();
+    let mut body = String::new();
+    body.push_str(
+        "# HELP rpa_circuit_state Circuit state of upstream, 0: closed, 1: half open, 2: open.\n",
+    );
+    body.push_str("# TYPE rpa_circuit_state gauge\n");
+    for s in &states {
+        body.push_str(&format!(
+            "rpa_circuit_state{{upstream=\"{}\"}} {}\n",
+            s.upstream,
+            s.state.gauge()
+        ));
+    }
+    body.push_str("# HELP rpa_circuit_failures Consecutive failures of upstream.\n");
+    body.push_str("# TYPE rpa_circuit_failures gauge\n");
+    for s in &states {
+        body.push_str(&format!(
+            "rpa_circuit_failures{{upstream=\"{}\"}} {}\n",
+            s.upstream, s.failures
+        ));
+    }
+    body.push_str("# HELP rpa_circuit_opened_total Times the circuit of upstream opened.\n");
+    body.push_str("# TYPE rpa_circuit_opened_total counter\n");
+    for s in &states {
+        body.push_str(&format!(
+            "rpa_circuit_opened_total{{upstream=\"{}\"}} {}\n",
+            s.upstream, s.opened_total
+        ));
+    }
+
+    body.push_str("# HELP rpa_leader Whether the replica runs the scheduled jobs.\n");
+    body.push_str("# TYPE rpa_leader gauge\n");
+    body.push_str(&format!(
+        "rpa_leader {}\n",
+        if app.leader.is_leader() { 1 } else { 0 }
+    ));
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        body,
+    )
+}
+
+// admin API is allowed for the users in server.admins.
+fn ensure_admin(app: &conf::AppState, ctx: &ReqContext) -> Result<(), HTTPError> {
+    valid_user(ctx.user)?;
+    if !app.admins.contains(&ctx.user) {
+        return Err(HTTPError::new(403, "forbidden".to_string()));
+    }
+    Ok(())
+}
+
+// notifications are pushed by the users in server.notifiers.
+fn ensure_notifier(app: &conf::AppState, ctx: &ReqContext) -> Result<(), HTTPError> {
+    valid_user(ctx.user)?;
+    if !app.notifiers.contains(&ctx.user) {
+        return Err(HTTPError::new(403, "forbidden".to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogLevels {
+    pub level: String, // the default level
+    pub targets: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogLevelInput {
+    pub target: Option<String>, // None sets the default level
+    pub level: String,          // empty removes the target's level
+}
+
+pub async fn get_log_levels(
+    State(app): State<Arc<conf::AppState>>,
+    to: PackObject<()>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+) -> Result<PackObject<SuccessResponse<LogLevels>>, HTTPError> {
+    ensure_admin(&app, &ctx)?;
+    let (level, targets) = app.log_levels.list();
+    Ok(to.with(SuccessResponse::new(LogLevels { level, targets })))
+}
+
+pub async fn set_log_level(
+    State(app): State<Arc<conf::AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    input: PackObject<LogLevelInput>,
+) -> Result<PackObject<SuccessResponse<LogLevels>>, HTTPError> {
+    ensure_admin(&app, &ctx)?;
+    let input = input.unwrap();
+    ctx.set_kvs(vec![
+        ("action", "set_log_level".into()),
+        ("target", input.target.clone().unwrap_or_default().into()),
+        ("level", input.level.clone().into()),
+    ])
+    .await;
+
+    app.log_levels
+        .set(input.target.as_deref(), &input.level)
+        .map_err(|err| HTTPError::new(400, err.to_string()))?;
+    let (level, targets) = app.log_levels.list();
+    Ok(to.with(SuccessResponse::new(LogLevels { level, targets })))
+}
+
+pub async fn query_audit(
+    State(app): State<Arc<conf::AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    input: Query<audit::Query>,
+) -> Result<PackObject<SuccessResponse<Vec<audit::Entry>>>, HTTPError> {
+    ensure_admin(&app, &ctx)?;
+    let input = input.0;
+    if input.gid.is_none() && input.cid.is_none() {
+        return Err(HTTPError::new(400, "gid or cid is required".to_string()));
+    }
+    if !app.audit.enabled() {
+        return Err(HTTPError::new(
+            404,
+            "audit log is not configured".to_string(),
+        ));
+    }
+    ctx.set_kvs(vec![
+        ("action", "query_audit".into()),
+        ("gid", input.gid.clone().unwrap_or_default().into()),
+        ("cid", input.cid.clone().unwrap_or_default().into()),
+    ])
+    .await;
+
+    let entries = app
+        .audit
+        .query(&input)
+        .map_err(|err| HTTPError::new(500, err.to_string()))?;
+    let mut res = SuccessResponse::new(entries);
+    res.total_size = Some(res.result.len() as u64);
+    Ok(to.with(res))
+}
+
+pub async fn list_jobs(
+    State(app): State<Arc<conf::AppState>>,
+    to: PackObject<()>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+) -> Result<PackObject<SuccessResponse<Vec<JobInfo>>>, HTTPError> {
+    ensure_admin(&app, &ctx)?;
+    Ok(to.with(SuccessResponse::new(app.jobs.list())))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JobInput {
+    pub name: String,
+}
+
+pub async fn pause_job(
+    State(app): State<Arc<conf::AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    input: PackObject<JobInput>,
+) -> Result<PackObject<SuccessResponse<JobInfo>>, HTTPError> {
+    set_job_paused(app, ctx, to, input.unwrap(), true).await
+}
+
+pub async fn resume_job(
+    State(app): State<Arc<conf::AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    input: PackObject<JobInput>,
+) -> Result<PackObject<SuccessResponse<JobInfo>>, HTTPError> {
+    set_job_paused(app, ctx, to, input.unwrap(), false).await
+}
+
+async fn set_job_paused(
+    app: Arc<conf::AppState>,
+    ctx: Arc<ReqContext>,
+    to: PackObject<()>,
+    input: JobInput,
+    paused: bool,
+) -> Result<PackObject<SuccessResponse<JobInfo>>, HTTPError> {
+    ensure_admin(&app, &ctx)?;
+    ctx.set_kvs(vec![
+        (
+            "action",
+            if paused { "pause_job" } else { "resume_job" }.into(),
+        ),
+        ("job", input.name.clone().into()),
+    ])
+    .await;
+
+    let info = app.jobs.set_paused(&input.name, paused).map_err(|err| {
+        match err.downcast_ref::<NotFound>() {
+            Some(_) => HTTPError::new(404, err.to_string()),
+            None => HTTPError::new(500, err.to_string()),
+        }
+    })?;
+    Ok(to.with(SuccessResponse::new(info)))
+}
+
+// takes a notification pushed by taskbase for immediate review, the result is
+// false if the task is being handled or left for the cron run.
+pub async fn push_notification(
+    State(app): State<Arc<conf::AppState>>,
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    to: PackObject<()>,
+    input: PackObject<NotificationOutput>,
+) -> Result<PackObject<SuccessResponse<bool>>, HTTPError> {
+    ensure_notifier(&app, &ctx)?;
+    let input = input.unwrap();
+    ctx.set_kvs(vec![
+        ("action", "push_notification".into()),
+        ("tid", input.tid.to_string().into()),
+        ("kind", input.kind.clone().into()),
+    ])
+    .await;
+
+    if input.kind == PUBLISH_KIND {
+        // scheduled items are published by the cron job in time.
+        return Ok(to.with(SuccessResponse::new(false)));
+    }
+    if let Err(err) = cbor_from_slice::<PublicationInput>(&input.payload) {
+        return Err(HTTPError::new(400, format!("invalid payload, {}", err)));
+    }
+    Ok(to.with(SuccessResponse::new(app.inbox.push(input))))
+}
+
 pub async fn new(state: Arc<conf::AppState>) -> anyhow::Result<Router> {
     let mds = ServiceBuilder::new()
         .layer(CatchPanicLayer::new())
         .layer(middleware::from_fn(context::middleware))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            telemetry::middleware,
+        ))
         .layer(CompressionLayer::new().compress_when(SizeAbove::new(encoding::MIN_ENCODING_SIZE)));
 
     let app = Router::new()
         .route("/", routing::get(version))
         .route("/healthz", routing::get(version))
+        .route("/readyz", routing::get(readyz))
+        .route("/metrics", routing::get(metrics))
+        .route("/v1/audit", routing::get(query_audit))
+        .route("/v1/jobs", routing::get(list_jobs))
+        .route("/v1/notifications", routing::post(push_notification))
+        .route("/v1/jobs/pause", routing::post(pause_job))
+        .route("/v1/jobs/resume", routing::post(resume_job))
+        .route(
+            "/v1/admin/log",
+            routing::get(get_log_levels).patch(set_log_level),
+        )
         .route_layer(mds)
         .with_state(state);
 
@@ -0,0 +1,299 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::conf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl State {
+    // value of the state gauge in metrics.
+    pub fn gauge(&self) -> u8 {
+        match self {
+            State::Closed => 0,
+            State::HalfOpen => 1,
+            State::Open => 2,
+        }
+    }
+}
+
+// Unavailable is returned when the circuit of an upstream is open, e.g. the
+// request opened it, the task being handled should be kept for a later run.
+#[derive(Debug)]
+pub struct Unavailable {
+    pub upstream: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upstream {} unavailable: {}", self.upstream, self.reason)
+    }
+}
+
+impl Error for Unavailable {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CircuitState {
+    pub upstream: String,
+    pub state: State,
+    pub failures: u32,
+    pub opened_total: u64,
+}
+
+struct Circuit {
+    state: State,
+    failures: u32,      // consecutive failures
+    probes: u32,        // requests in flight while half open
+    opened_at: Instant, // valid when open
+    opened_total: u64,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Self {
+            state: State::Closed,
+            failures: 0,
+            probes: 0,
+            opened_at: Instant::now(),
+            opened_total: 0,
+        }
+    }
+}
+
+// Breakers holds a circuit for every upstream base URL. A circuit opens after
+// `failure_threshold` consecutive failures and short-circuits the calls while open.
+// After `open_timeout` it becomes half open and lets `half_open_requests` probe
+// requests through, a successful probe closes it and a failed one opens it again.
+pub struct Breakers {
+    failure_threshold: u32,
+    open_timeout: Duration,
+    half_open_requests: u32,
+    circuits: Mutex<BTreeMap<String, Circuit>>,
+}
+
+impl Breakers {
+    pub fn new(cfg: &conf::Breaker) -> Self {
+        Self {
+            failure_threshold: cfg.failure_threshold.max(1),
+            open_timeout: Duration::from_secs(cfg.open_timeout),
+            half_open_requests: cfg.half_open_requests.max(1),
+            circuits: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn register(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        circuits
+            .entry(upstream.to_string())
+            .or_insert_with(Circuit::new);
+    }
+
+    // checks whether a request to the upstream is allowed, its result is recorded
+    // by the returned permit.
+    pub fn acquire(&self, upstream: &str) -> Result<Permit<'_>, Unavailable> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(upstream.to_string())
+            .or_insert_with(Circuit::new);
+
+        if circuit.state == State::Open {
+            if circuit.opened_at.elapsed() < self.open_timeout {
+                return Err(Unavailable {
+                    upstream: upstream.to_string(),
+                    reason: "circuit open".to_string(),
+                });
+            }
+            circuit.state = State::HalfOpen;
+            circuit.probes = 0;
+        }
+
+        if circuit.state == State::HalfOpen {
+            if circuit.probes >= self.half_open_requests {
+                return Err(Unavailable {
+                    upstream: upstream.to_string(),
+                    reason: "circuit half open".to_string(),
+                });
+            }
+            circuit.probes += 1;
+        }
+        Ok(Permit {
+            breakers: self,
+            upstream: upstream.to_string(),
+            probe: circuit.state == State::HalfOpen,
+        })
+    }
+
+    // gives a probe back to the half open circuit.
+    fn release(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(upstream) {
+            if circuit.state == State::HalfOpen {
+                circuit.probes = circuit.probes.saturating_sub(1);
+            }
+        }
+    }
+
+    // records the result of a request that was allowed by acquire, returns the
+    // state of the circuit then.
+    fn record(&self, upstream: &str, probe: bool, success: bool) -> State {
+        if probe {
+            self.release(upstream);
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(upstream.to_string())
+            .or_insert_with(Circuit::new);
+
+        if success {
+            circuit.state = State::Closed;
+            circuit.failures = 0;
+            return circuit.state;
+        }
+
+        circuit.failures += 1;
+        if circuit.state == State::HalfOpen || circuit.failures >= self.failure_threshold {
+            if circuit.state != State::Open {
+                circuit.opened_total += 1;
+                log::warn!(target: "job",
+                    action = "circuit_open",
+                    upstream = upstream,
+                    failures = circuit.failures;
+                    "",
+                );
+            }
+            circuit.state = State::Open;
+            circuit.opened_at = Instant::now();
+        }
+        circuit.state
+    }
+
+    pub fn states(&self) -> Vec<CircuitState> {
+        let circuits = self.circuits.lock().unwrap();
+        circuits
+            .iter()
+            .map(|(upstream, c)| CircuitState {
+                upstream: upstream.clone(),
+                state: c.state,
+                failures: c.failures,
+                opened_total: c.opened_total,
+            })
+            .collect()
+    }
+}
+
+// Permit is a request allowed by the circuit. A probe of a half open circuit that is
+// dropped without its result, e.g. the request is cancelled, is given back.
+pub struct Permit<'a> {
+    breakers: &'a Breakers,
+    upstream: String,
+    probe: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) -> State {
+        let probe = std::mem::take(&mut self.probe);
+        self.breakers.record(&self.upstream, probe, success)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breakers.release(&self.upstream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_states() {
+        let upstream = "http://127.0.0.1:8080";
+        let breakers = Breakers::new(&conf::Breaker {
+            failure_threshold: 2,
+            open_timeout: 3600,
+            half_open_requests: 1,
+        });
+        breakers.register(upstream);
+        assert_eq!(breakers.states()[0].state, State::Closed);
+
+        breakers.acquire(upstream).unwrap().record(false);
+        breakers.acquire(upstream).unwrap().record(true);
+        assert_eq!(breakers.states()[0].failures, 0);
+
+        breakers.acquire(upstream).unwrap().record(false);
+        breakers.acquire(upstream).unwrap().record(false);
+        let state = &breakers.states()[0];
+        assert_eq!(state.state, State::Open);
+        assert_eq!(state.opened_total, 1);
+        assert_eq!(
+            breakers.acquire(upstream).err().unwrap().to_string(),
+            "upstream http://127.0.0.1:8080 unavailable: circuit open"
+        );
+    }
+
+    #[test]
+    fn half_open_probes() {
+        let upstream = "http://127.0.0.1:8080";
+        let breakers = Breakers::new(&conf::Breaker {
+            failure_threshold: 1,
+            open_timeout: 0,
+            half_open_requests: 1,
+        });
+
+        breakers.acquire(upstream).unwrap().record(false);
+        assert_eq!(breakers.states()[0].state, State::Open);
+
+        // open timeout elapsed, only one probe is allowed
+        let probe = breakers.acquire(upstream).unwrap();
+        assert_eq!(breakers.states()[0].state, State::HalfOpen);
+        assert!(breakers.acquire(upstream).is_err());
+
+        // failed probe opens the circuit again
+        probe.record(false);
+        assert_eq!(breakers.states()[0].state, State::Open);
+        assert_eq!(breakers.states()[0].opened_total, 2);
+
+        breakers.acquire(upstream).unwrap().record(true);
+        assert_eq!(breakers.states()[0].state, State::Closed);
+    }
+
+    #[tokio::test]
+    async fn cancelled_probe() {
+        let upstream = "http://127.0.0.1:8080";
+        let breakers = Breakers::new(&conf::Breaker {
+            failure_threshold: 1,
+            open_timeout: 0,
+            half_open_requests: 1,
+        });
+        breakers.acquire(upstream).unwrap().record(false);
+
+        // the probe request is cancelled by a timeout before its result
+        let res = tokio::time::timeout(Duration::from_millis(10), async {
+            let _probe = breakers.acquire(upstream).unwrap();
+            std::future::pending::<()>().await
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(breakers.states()[0].state, State::HalfOpen);
+
+        let probe = breakers.acquire(upstream).unwrap();
+        assert!(breakers.acquire(upstream).is_err());
+        probe.record(true);
+        assert_eq!(breakers.states()[0].state, State::Closed);
+    }
+}
@@ -0,0 +1,205 @@
+use futures::future::{pending, select_all, BoxFuture, FutureExt};
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::watch, time::sleep};
+
+// Cancel asks the runs to stop, on shutdown or when a run is superseded.
+#[derive(Clone)]
+pub struct Cancel {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Cancel {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self { tx: Arc::new(tx) }
+    }
+}
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    pub async fn cancelled(&self) {
+        let mut rx = self.tx.subscribe();
+        while !*rx.borrow_and_update() {
+            // the sender lives as long as self.
+            let _ = rx.changed().await;
+        }
+    }
+
+    pub fn same(&self, other: &Cancel) -> bool {
+        Arc::ptr_eq(&self.tx, &other.tx)
+    }
+}
+
+// Cancelled is returned for the work that is left untouched for the next run.
+#[derive(Debug)]
+pub struct Cancelled(pub String);
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled, {}", self.0)
+    }
+}
+
+impl Error for Cancelled {}
+
+// Scope bounds a run by its deadline and cancellation, and every item of the run
+// by the item timeout. Zero durations mean no limit.
+pub struct Scope {
+    cancels: Vec<Cancel>,
+    deadline: Option<Instant>,
+    item_timeout: Option<Duration>,
+}
+
+impl Scope {
+    pub fn new(cancels: Vec<Cancel>, run_timeout: Duration, item_timeout: Duration) -> Self {
+        Self {
+            cancels,
+            deadline: (!run_timeout.is_zero()).then(|| Instant::now() + run_timeout),
+            item_timeout: (!item_timeout.is_zero()).then_some(item_timeout),
+        }
+    }
+
+    // returns Cancelled if the run should stop.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.cancels.iter().any(|c| c.is_cancelled()) {
+            return Err(Cancelled("shutdown or superseded".to_string()).into());
+        }
+        match self.deadline {
+            Some(deadline) if deadline <= Instant::now() => {
+                Err(Cancelled("run deadline exceeded".to_string()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // runs an item, it is dropped if it does not finish in time or the run is cancelled.
+    pub async fn run<T>(&self, fut: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        self.check()?;
+        let (wait, reason) = match (self.item_timeout, self.deadline) {
+            (Some(timeout), Some(deadline))
+                if deadline.saturating_duration_since(Instant::now()) < timeout =>
+            {
+                (
+                    Some(deadline.saturating_duration_since(Instant::now())),
+                    "run deadline exceeded",
+                )
+            }
+            (None, Some(deadline)) => (
+                Some(deadline.saturating_duration_since(Instant::now())),
+                "run deadline exceeded",
+            ),
+            (timeout, _) => (timeout, "item timed out"),
+        };
+        tokio::select! {
+            res = fut => res,
+            _ = self.sleep_or_pending(wait) => Err(Cancelled(reason.to_string()).into()),
+            _ = self.cancelled() => Err(Cancelled("shutdown or superseded".to_string()).into()),
+        }
+    }
+
+    // sleeps for the duration unless the run is cancelled or its deadline comes first.
+    pub async fn sleep(&self, duration: Duration) -> anyhow::Result<()> {
+        self.check()?;
+        if let Some(deadline) = self.deadline {
+            if Instant::now() + duration > deadline {
+                return Err(Cancelled("run deadline exceeded".to_string()).into());
+            }
+        }
+        tokio::select! {
+            _ = sleep(duration) => Ok(()),
+            _ = self.cancelled() => Err(Cancelled("shutdown or superseded".to_string()).into()),
+        }
+    }
+
+    async fn sleep_or_pending(&self, wait: Option<Duration>) {
+        match wait {
+            Some(wait) => sleep(wait).await,
+            None => pending().await,
+        }
+    }
+
+    async fn cancelled(&self) {
+        if self.cancels.is_empty() {
+            return pending().await;
+        }
+        let futs: Vec<BoxFuture<'_, ()>> =
+            self.cancels.iter().map(|c| c.cancelled().boxed()).collect();
+        select_all(futs).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_cancelled(res: anyhow::Result<()>, reason: &str) -> bool {
+        match res {
+            Err(err) => err
+                .downcast_ref::<Cancelled>()
+                .map_or(false, |c| c.0 == reason),
+            Ok(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn scope() {
+        let slow = || async {
+            sleep(Duration::from_millis(200)).await;
+            Ok(())
+        };
+
+        let cancel = Cancel::default();
+        let scope = Scope::new(
+            vec![cancel.clone()],
+            Duration::from_millis(150),
+            Duration::from_millis(50),
+        );
+        assert!(scope.run(async { Ok(()) }).await.is_ok());
+        assert!(is_cancelled(scope.run(slow()).await, "item timed out"));
+        assert!(scope.check().is_ok());
+        assert!(is_cancelled(
+            scope.sleep(Duration::from_millis(200)).await,
+            "run deadline exceeded"
+        ));
+        sleep(Duration::from_millis(60)).await;
+        assert!(is_cancelled(
+            scope.run(slow()).await,
+            "run deadline exceeded"
+        ));
+        assert!(is_cancelled(scope.check(), "run deadline exceeded"));
+
+        // cancelled while running
+        let scope = Scope::new(
+            vec![Cancel::default(), cancel.clone()],
+            Duration::ZERO,
+            Duration::ZERO,
+        );
+        let c = cancel.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(20)).await;
+            c.cancel();
+        });
+        let start = Instant::now();
+        assert!(is_cancelled(
+            scope.run(slow()).await,
+            "shutdown or superseded"
+        ));
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(is_cancelled(scope.check(), "shutdown or superseded"));
+        assert!(cancel.same(&cancel.clone()));
+        assert!(!cancel.same(&Cancel::default()));
+    }
+}
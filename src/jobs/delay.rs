@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::timeout};
+
+use axum_web::context::unix_ms;
+
+use super::NotificationOutput;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Delayed {
+    pub due_at: u64, // unix milliseconds
+    pub item: NotificationOutput,
+}
+
+// DelayQueue holds the todo items that are not eligible yet until they are due.
+// It is bounded, and every item is kept in the storage under the prefix.
+pub struct DelayQueue {
+    storage: Arc<dyn Storage>,
+    prefix: String,
+    capacity: usize,
+    items: Mutex<BTreeMap<String, Delayed>>,
+    notify: Notify,
+}
+
+impl DelayQueue {
+    pub fn new(storage: Arc<dyn Storage>, prefix: &str, capacity: usize) -> Self {
+        let mut items = BTreeMap::new();
+        for key in storage.keys(prefix).unwrap_or_default() {
+            if let Ok(Some(delayed)) = storage.get_object::<Delayed>(&key) {
+                items.insert(key[prefix.len()..].to_string(), delayed);
+            }
+        }
+        Self {
+            storage,
+            prefix: prefix.to_string(),
+            capacity,
+            items: Mutex::new(items),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn contains(&self, tid: &str) -> bool {
+        self.items.lock().unwrap().contains_key(tid)
+    }
+
+    // queues the item until due_at, returns false if the queue is full.
+    pub fn push(&self, due_at: u64, item: NotificationOutput) -> anyhow::Result<bool> {
+        let tid = item.tid.to_string();
+        let delayed = Delayed { due_at, item };
+        {
+            let mut items = self.items.lock().unwrap();
+            if items.len() >= self.capacity && !items.contains_key(&tid) {
+                return Ok(false);
+            }
+            self.storage
+                .set_object(&format!("{}{}", self.prefix, tid), &delayed)?;
+            items.insert(tid, delayed);
+        }
+        self.notify.notify_one();
+        Ok(true)
+    }
+
+    // drops the items whose task is not kept, e.g. handled by humans.
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) {
+        let mut items = self.items.lock().unwrap();
+        let gone: Vec<String> = items.keys().filter(|tid| !keep(tid)).cloned().collect();
+        for tid in gone {
+            items.remove(&tid);
+            let _ = self.storage.delete(&format!("{}{}", self.prefix, tid));
+        }
+    }
+
+    // returns the items in order of due time.
+    pub fn list(&self) -> Vec<Delayed> {
+        let items = self.items.lock().unwrap();
+        let mut list: Vec<Delayed> = items.values().cloned().collect();
+        list.sort_by_key(|d| d.due_at);
+        list
+    }
+
+    // waits for the next item that is due and removes it from the queue.
+    pub async fn next(&self) -> NotificationOutput {
+        loop {
+            let wait = {
+                let mut items = self.items.lock().unwrap();
+                let now = unix_ms();
+                match items.iter().min_by_key(|(_, d)| d.due_at) {
+                    None => None,
+                    Some((tid, d)) if d.due_at <= now => {
+                        let tid = tid.clone();
+                        let _ = self.storage.delete(&format!("{}{}", self.prefix, tid));
+                        return items.remove(&tid).unwrap().item;
+                    }
+                    Some((_, d)) => Some(Duration::from_millis(d.due_at - now)),
+                }
+            };
+            match wait {
+                None => self.notify.notified().await,
+                Some(wait) => {
+                    let _ = timeout(wait, self.notify.notified()).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+    use axum_web::object::PackObject;
+
+    #[tokio::test]
+    async fn delay_queue() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStore::default());
+        let queue = DelayQueue::new(storage.clone(), "review:delayed:", 2);
+        let item = |tid: xid::Id| NotificationOutput {
+            tid: PackObject::Cbor(tid),
+            ..Default::default()
+        };
+        let tids = [xid::new(), xid::new(), xid::new()];
+        let now = unix_ms();
+
+        assert!(queue.push(now + 100, item(tids[0])).unwrap());
+        assert!(queue.push(now + 20, item(tids[1])).unwrap());
+        // full
+        assert!(!queue.push(now, item(tids[2])).unwrap());
+        // updated
+        assert!(queue.push(now + 50, item(tids[0])).unwrap());
+        assert!(queue.contains(&tids[0].to_string()));
+        assert_eq!(queue.list()[0].due_at, now + 20);
+
+        // persisted
+        let reloaded = DelayQueue::new(storage.clone(), "review:delayed:", 2);
+        assert_eq!(reloaded.list().len(), 2);
+
+        assert_eq!(*queue.next().await.tid, tids[1]);
+        assert!(unix_ms() >= now + 20);
+        queue.retain(|_| false);
+        assert!(queue.list().is_empty());
+        assert!(storage.keys("review:delayed:").unwrap().is_empty());
+
+        // woken up by a push
+        let queue = Arc::new(queue);
+        let q = queue.clone();
+        let next = tokio::spawn(async move { q.next().await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(queue.push(unix_ms(), item(tids[2])).unwrap());
+        assert_eq!(*next.await.unwrap().tid, tids[2]);
+    }
+}
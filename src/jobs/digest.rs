@@ -0,0 +1,436 @@
+use async_trait::async_trait;
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use axum_web::{
+    context::{unix_ms, ReqContext},
+    object::PackObject,
+};
+
+use super::{cancel::Scope, RPA, X_REQUEST_ID};
+use crate::{audit, conf};
+
+// storage key prefix of the items waiting for the next digest
+const DIGEST_ITEMS: &str = "digest:items:";
+
+// an escalated or failed item that human reviewers should know about.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct DigestItem {
+    pub ts: u64,      // unix milliseconds
+    pub kind: String, // "escalated" or "failed"
+    pub tid: String,
+    pub gid: String,
+    pub cid: String,
+    pub language: String,
+    pub version: i16,
+    pub reason: String,
+    pub to: Vec<String>, // reviewers not notified yet
+}
+
+// the digest sent to a reviewer.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DigestMessage {
+    pub reviewer: String,
+    pub subject: String,
+    pub body: String,
+    pub items: Vec<DigestItem>,
+}
+
+// Notifier delivers the digests to the reviewers.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn notify(&self, ctx: &ReqContext, msg: &DigestMessage) -> anyhow::Result<()>;
+}
+
+// FileNotifier appends the digests as JSON lines to a local file.
+pub struct FileNotifier {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileNotifier {
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        Ok(Self {
+            path,
+            lock: Mutex::new(()),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    async fn notify(&self, _ctx: &ReqContext, msg: &DigestMessage) -> anyhow::Result<()> {
+        let mut data = serde_json::to_vec(msg)?;
+        data.push(b'\n');
+        let _lock = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&data)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// WebhookNotifier POSTs the digests as JSON to an endpoint, e.g. a chat or mail gateway.
+// Any 2xx response is a success.
+pub struct WebhookNotifier {
+    client: Client,
+    endpoint: reqwest::Url,
+}
+
+impl WebhookNotifier {
+    pub fn new(endpoint: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: super::new_external_client()?,
+            endpoint: reqwest::Url::parse(endpoint)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn notify(&self, ctx: &ReqContext, msg: &DigestMessage) -> anyhow::Result<()> {
+        let res = self
+            .client
+            .post(self.endpoint.clone())
+            .header(header::ACCEPT, "application/json")
+            .header(&X_REQUEST_ID, &ctx.rid)
+            .json(msg)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            anyhow::bail!("webhook responded {}, {}", status, body);
+        }
+        Ok(())
+    }
+}
+
+// replaces the "{name}" placeholders in the template, unknown ones are kept.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    // a single pass, placeholders in the values are not replaced.
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            vars.iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                out.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Digest gathers the escalated and failed items and sends them to the reviewers
+// through the notifier.
+pub struct Digest {
+    notifier: Option<Box<dyn Notifier>>,
+    subject: String,
+    body: String,
+    item: String,
+    link: String,
+}
+
+impl Digest {
+    pub fn new(cfg: &conf::Digest) -> anyhow::Result<Self> {
+        let notifier: Option<Box<dyn Notifier>> = match cfg.notifier.as_str() {
+            "" => None,
+            "file" => Some(Box::new(FileNotifier::new(&cfg.path)?)),
+            "webhook" => Some(Box::new(WebhookNotifier::new(&cfg.endpoint)?)),
+            kind => anyhow::bail!("invalid notifier {:?}", kind),
+        };
+        Ok(Self {
+            notifier,
+            subject: cfg.subject.clone(),
+            body: cfg.body.clone(),
+            item: cfg.item.clone(),
+            link: cfg.link.clone(),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.notifier.is_some()
+    }
+
+    pub fn render(&self, reviewer: &str, items: Vec<DigestItem>) -> DigestMessage {
+        let count = items.len().to_string();
+        let mut lines = String::new();
+        for item in &items {
+            let version = item.version.to_string();
+            let vars = [
+                ("kind", item.kind.as_str()),
+                ("tid", item.tid.as_str()),
+                ("gid", item.gid.as_str()),
+                ("cid", item.cid.as_str()),
+                ("language", item.language.as_str()),
+                ("version", version.as_str()),
+                ("reason", item.reason.as_str()),
+            ];
+            let link = render(&self.link, &vars);
+            let mut vars = vars.to_vec();
+            vars.push(("link", link.as_str()));
+            lines.push_str(&render(&self.item, &vars));
+        }
+        let vars = [
+            ("reviewer", reviewer),
+            ("count", count.as_str()),
+            ("items", lines.as_str()),
+        ];
+        DigestMessage {
+            reviewer: reviewer.to_string(),
+            subject: render(&self.subject, &vars),
+            body: render(&self.body, &vars),
+            items,
+        }
+    }
+}
+
+impl RPA {
+    // keeps the item for the next digest of the reviewers.
+    pub(super) fn record_digest(
+        &self,
+        ctx: &ReqContext,
+        kind: &str,
+        to: &[PackObject<xid::Id>],
+        entry: &audit::Entry,
+    ) {
+        if !self.digest.enabled() || to.is_empty() {
+            return;
+        }
+        let item = DigestItem {
+            ts: unix_ms(),
+            kind: kind.to_string(),
+            tid: entry.tid.clone(),
+            gid: entry.gid.clone(),
+            cid: entry.cid.clone(),
+            language: entry.language.clone(),
+            version: entry.version,
+            reason: entry.reason.clone(),
+            to: to.iter().map(|uid| uid.to_string()).collect(),
+        };
+        let key = format!("{}{:013}:{}:{}", DIGEST_ITEMS, item.ts, item.tid, kind);
+        if let Err(err) = self.storage.set_object(&key, &item) {
+            log::warn!(target: "job",
+                action = "record_digest",
+                rid = &ctx.rid,
+                key = &key,
+                error = err.to_string();
+                "",
+            );
+        }
+    }
+
+    // sends a digest of the items since the last run to every reviewer, the items
+    // of a reviewer that fails are kept for the next run.
+    pub(super) async fn send_digests(
+        &self,
+        ctx: &ReqContext,
+        scope: &Scope,
+    ) -> anyhow::Result<BTreeMap<String, u64>> {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        let notifier = match &self.digest.notifier {
+            Some(notifier) => notifier,
+            None => return Ok(counts),
+        };
+
+        let mut items: BTreeMap<String, DigestItem> = BTreeMap::new();
+        for key in self.storage.keys(DIGEST_ITEMS)? {
+            if let Some(item) = self.storage.get_object::<DigestItem>(&key)? {
+                items.insert(key, item);
+            }
+        }
+        counts.insert("items".to_string(), items.len() as u64);
+        if items.is_empty() {
+            return Ok(counts);
+        }
+
+        let mut reviewers: BTreeMap<String, Vec<DigestItem>> = BTreeMap::new();
+        for item in items.values() {
+            for uid in &item.to {
+                reviewers.entry(uid.clone()).or_default().push(item.clone());
+            }
+        }
+
+        // the items are kept for the reviewers not sent to.
+        let mut sent: Vec<String> = Vec::new();
+        let mut stopped = None;
+        for (reviewer, list) in reviewers {
+            if let Err(err) = scope.check() {
+                stopped = Some(err);
+                break;
+            }
+            let ctx = ctx.child();
+            let msg = self.digest.render(&reviewer, list);
+            match scope.run(notifier.notify(&ctx, &msg)).await {
+                Ok(_) => {
+                    *counts.entry("sent".to_string()).or_default() += 1;
+                    log::info!(target: "job",
+                        action = "send_digest",
+                        rid = &ctx.rid,
+                        notifier = notifier.name(),
+                        reviewer = &reviewer,
+                        items = msg.items.len();
+                        "",
+                    );
+                    sent.push(reviewer);
+                }
+                Err(err) => {
+                    *counts.entry("failed".to_string()).or_default() += 1;
+                    log::error!(target: "job",
+                        action = "send_digest",
+                        rid = &ctx.rid,
+                        notifier = notifier.name(),
+                        reviewer = &reviewer,
+                        error = err.to_string();
+                        "failed",
+                    );
+                }
+            }
+        }
+
+        for (key, mut item) in items {
+            item.to.retain(|uid| !sent.contains(uid));
+            if item.to.is_empty() {
+                self.storage.delete(&key)?;
+            } else {
+                self.storage.set_object(&key, &item)?;
+            }
+        }
+        match stopped {
+            Some(err) => Err(err),
+            None => Ok(counts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::StatusCode, routing, Json, Router};
+    use std::sync::Arc;
+
+    use super::super::testing::test_rpa;
+
+    #[derive(Clone, Default)]
+    struct Webhook {
+        received: Arc<Mutex<Vec<DigestMessage>>>,
+        failing: Arc<Mutex<String>>, // reviewer that the webhook fails for
+    }
+
+    async fn webhook(State(hook): State<Webhook>, Json(msg): Json<DigestMessage>) -> StatusCode {
+        if msg.reviewer == *hook.failing.lock().unwrap() {
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        hook.received.lock().unwrap().push(msg);
+        StatusCode::NO_CONTENT
+    }
+
+    #[test]
+    fn render_once() {
+        let vars = [("reason", "a {tid} in the title"), ("tid", "t1")];
+        assert_eq!(
+            render("{{tid}}: {reason}, {unknown}", &vars),
+            "{t1}: a {tid} in the title, {unknown}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_digests() {
+        let hook = Webhook::default();
+        let app = Router::new()
+            .route("/digest", routing::post(webhook))
+            .with_state(hook.clone());
+        let (rpa, _) = test_rpa(app, |cfg, addr| {
+            cfg.digest.notifier = "webhook".to_string();
+            cfg.digest.endpoint = format!("http://{}/digest", addr);
+            cfg.digest.subject = "{count} items for {reviewer}".to_string();
+            cfg.digest.item = "{kind} {language}: {reason} {link}\n".to_string();
+            cfg.digest.link = "https://yiwen.ai/pub/{gid}/{cid}".to_string();
+        })
+        .await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let gid = PackObject::Cbor(xid::new());
+        let cid = PackObject::Cbor(xid::new());
+        let entry = audit::Entry::new(&ctx, &PackObject::Cbor(xid::new()))
+            .publication(&gid, &cid, "eng", 1)
+            .reason("matched keyword");
+        let reviewers = [PackObject::Cbor(xid::new()), PackObject::Cbor(xid::new())];
+        rpa.record_digest(&ctx, "escalated", &reviewers[..1], &entry);
+        rpa.record_digest(
+            &ctx,
+            "failed",
+            &reviewers,
+            &entry.clone().reason("invalid payload"),
+        );
+
+        *hook.failing.lock().unwrap() = reviewers[1].to_string();
+        let counts = rpa.send_digests(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("items"), Some(&2));
+        assert_eq!(counts.get("sent"), Some(&1));
+        assert_eq!(counts.get("failed"), Some(&1));
+        {
+            let received = hook.received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].reviewer, reviewers[0].to_string());
+            assert_eq!(
+                received[0].subject,
+                format!("2 items for {}", *reviewers[0])
+            );
+            assert!(received[0].body.contains(&format!(
+                "escalated eng: matched keyword https://yiwen.ai/pub/{}/{}\n",
+                *gid, *cid
+            )));
+            assert!(received[0].body.contains("failed eng: invalid payload"));
+        }
+
+        // the failed reviewer gets the items in the next run
+        hook.failing.lock().unwrap().clear();
+        let counts = rpa.send_digests(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("items"), Some(&1));
+        assert_eq!(counts.get("sent"), Some(&1));
+        assert_eq!(hook.received.lock().unwrap()[1].items.len(), 1);
+
+        let counts = rpa.send_digests(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("items"), Some(&0));
+    }
+}
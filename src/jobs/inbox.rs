@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use super::NotificationOutput;
+
+// the number of pushed notifications waiting to be handled.
+const INBOX_SIZE: usize = 1000;
+// how long a handled task is not claimed again, longer than the period of the review
+// and reaper jobs so that a todo list or a notification from before it was acked is
+// not handled twice.
+const HANDLED_TTL: Duration = Duration::from_secs(120);
+
+// Inbox takes the notifications pushed by the webhook for immediate handling.
+// A task is claimed while it is handled, so that the webhook and the cron run
+// do not handle it twice, and it is remembered for a while after it is handled.
+pub struct Inbox {
+    tx: mpsc::Sender<NotificationOutput>,
+    rx: Mutex<Option<mpsc::Receiver<NotificationOutput>>>,
+    claimed: Mutex<BTreeSet<String>>,
+    handled: Mutex<BTreeMap<String, Instant>>,
+}
+
+impl Default for Inbox {
+    fn default() -> Self {
+        Self::new(INBOX_SIZE)
+    }
+}
+
+impl Inbox {
+    pub fn new(size: usize) -> Self {
+        let (tx, rx) = mpsc::channel(size.max(1));
+        Self {
+            tx,
+            rx: Mutex::new(Some(rx)),
+            claimed: Mutex::new(BTreeSet::new()),
+            handled: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // returns false if the task is being handled or was handled recently.
+    pub fn claim(&self, tid: &str) -> bool {
+        if let Some(at) = self.handled.lock().unwrap().get(tid) {
+            if at.elapsed() < HANDLED_TTL {
+                return false;
+            }
+        }
+        self.claimed.lock().unwrap().insert(tid.to_string())
+    }
+
+    // releases the task that is left for another run.
+    pub fn release(&self, tid: &str) {
+        self.claimed.lock().unwrap().remove(tid);
+    }
+
+    // releases the task that has been acked, it is not claimed again until HANDLED_TTL.
+    pub fn done(&self, tid: &str) {
+        {
+            let mut handled = self.handled.lock().unwrap();
+            handled.retain(|_, at| at.elapsed() < HANDLED_TTL);
+            handled.insert(tid.to_string(), Instant::now());
+        }
+        self.release(tid);
+    }
+
+    // queues the notification with its task claimed, returns false if the task
+    // is being handled or the inbox is full, it is left for the cron run then.
+    pub fn push(&self, item: NotificationOutput) -> bool {
+        let tid = item.tid.to_string();
+        if !self.claim(&tid) {
+            return false;
+        }
+        if self.tx.try_send(item).is_err() {
+            self.release(&tid);
+            return false;
+        }
+        true
+    }
+
+    // takes the receiver, the handler releases the task of every item it receives.
+    pub fn take(&self) -> Option<mpsc::Receiver<NotificationOutput>> {
+        self.rx.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_web::object::PackObject;
+
+    #[tokio::test]
+    async fn push_and_claim() {
+        let inbox = Inbox::new(2);
+        let item = |tid: xid::Id| NotificationOutput {
+            tid: PackObject::Cbor(tid),
+            ..Default::default()
+        };
+        let tids = [xid::new(), xid::new(), xid::new()];
+
+        assert!(inbox.push(item(tids[0])));
+        // deduped
+        assert!(!inbox.push(item(tids[0])));
+        // claimed by the cron run
+        assert!(inbox.claim(&tids[1].to_string()));
+        assert!(!inbox.push(item(tids[1])));
+        inbox.release(&tids[1].to_string());
+        assert!(inbox.push(item(tids[1])));
+        // full
+        assert!(!inbox.push(item(tids[2])));
+        assert!(inbox.claim(&tids[2].to_string()));
+
+        let mut rx = inbox.take().unwrap();
+        assert!(inbox.take().is_none());
+        let got = rx.recv().await.unwrap();
+        assert_eq!(*got.tid, tids[0]);
+        assert!(!inbox.claim(&tids[0].to_string()));
+        inbox.release(&tids[0].to_string());
+        assert!(inbox.claim(&tids[0].to_string()));
+    }
+
+    #[test]
+    fn handled_recently() {
+        let inbox = Inbox::new(2);
+        let tid = xid::new();
+        let item = NotificationOutput {
+            tid: PackObject::Cbor(tid),
+            ..Default::default()
+        };
+
+        assert!(inbox.claim(&tid.to_string()));
+        inbox.done(&tid.to_string());
+        // neither the cron run nor the webhook handles it again
+        assert!(!inbox.claim(&tid.to_string()));
+        assert!(!inbox.push(item.clone()));
+
+        // expired
+        inbox
+            .handled
+            .lock()
+            .unwrap()
+            .insert(tid.to_string(), Instant::now() - HANDLED_TTL);
+        assert!(inbox.push(item));
+    }
+}
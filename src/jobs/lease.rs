@@ -0,0 +1,473 @@
+use async_trait::async_trait;
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::sleep;
+
+use crate::conf;
+use axum_web::{
+    context::{unix_ms, ReqContext},
+    encoding::Encoding,
+    object::PackObject,
+};
+
+// a guard file older than this is left by a crashed process.
+const STALE_GUARD: Duration = Duration::from_secs(10);
+// waits for another replica that is changing the lease file.
+const GUARD_RETRIES: u32 = 5;
+const GUARD_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+// Lease is a lock with a time to live that one holder owns at a time.
+#[async_trait]
+pub trait Lease: Send + Sync {
+    fn name(&self) -> &str;
+
+    // acquires or renews the lease for ttl, returns false if another holder owns it.
+    async fn acquire(&self, holder: &str, ttl: Duration) -> anyhow::Result<bool>;
+
+    // releases the lease if the holder owns it.
+    async fn release(&self, holder: &str) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LeaseState {
+    pub name: String,
+    pub holder: String,
+    pub expires_at: u64, // unix milliseconds
+}
+
+// FileLease keeps the lease state in a file, for replicas on a single host.
+// Changes are serialized by a guard file that is created exclusively.
+pub struct FileLease {
+    name: String,
+    path: PathBuf,
+    guard: PathBuf,
+}
+
+impl FileLease {
+    pub fn new(name: &str, path: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let mut guard = path.clone().into_os_string();
+        guard.push(".guard");
+        Ok(Self {
+            name: name.to_string(),
+            path,
+            guard: PathBuf::from(guard),
+        })
+    }
+
+    fn lock(&self) -> anyhow::Result<Option<File>> {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.guard)
+        {
+            Ok(file) => Ok(Some(file)),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                if is_stale(&self.guard) {
+                    self.remove_stale_guard();
+                }
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // moves the guard away before removing it, so that of the replicas that found it
+    // stale only one takes it, and a fresh guard created in the meantime is put back.
+    fn remove_stale_guard(&self) {
+        let mut taken = self.guard.clone().into_os_string();
+        taken.push(format!(".{}", uuid::Uuid::new_v4()));
+        let taken = PathBuf::from(taken);
+        if fs::rename(&self.guard, &taken).is_err() {
+            return;
+        }
+        if !is_stale(&taken) {
+            // fails if yet another replica holds the guard now, it retries then.
+            let _ = fs::hard_link(&taken, &self.guard);
+        }
+        let _ = fs::remove_file(&taken);
+    }
+
+    fn read(&self) -> anyhow::Result<LeaseState> {
+        match fs::read(&self.path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(LeaseState::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, state: &LeaseState) -> anyhow::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    // changes the lease state, it fails if another replica keeps changing it.
+    async fn update(&self, f: impl Fn(LeaseState) -> Option<LeaseState>) -> anyhow::Result<bool> {
+        let mut retries = 0;
+        while self.lock()?.is_none() {
+            if retries >= GUARD_RETRIES {
+                anyhow::bail!("lease file is being changed by another replica");
+            }
+            retries += 1;
+            sleep(GUARD_RETRY_INTERVAL).await;
+        }
+        let res = self.read().and_then(|state| match f(state) {
+            Some(state) => self.write(&state).map(|_| true),
+            None => Ok(false),
+        });
+        fs::remove_file(&self.guard)?;
+        res
+    }
+}
+
+fn is_stale(guard: &Path) -> bool {
+    fs::metadata(guard)
+        .and_then(|m| m.modified())
+        .map(|t| {
+            SystemTime::now()
+                .duration_since(t)
+                .map_or(false, |d| d > STALE_GUARD)
+        })
+        .unwrap_or(false)
+}
+
+#[async_trait]
+impl Lease for FileLease {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    async fn acquire(&self, holder: &str, ttl: Duration) -> anyhow::Result<bool> {
+        let now = unix_ms();
+        self.update(|state| {
+            if state.holder != holder && !state.holder.is_empty() && state.expires_at > now {
+                return None;
+            }
+            Some(LeaseState {
+                name: self.name.clone(),
+                holder: holder.to_string(),
+                expires_at: now + ttl.as_millis() as u64,
+            })
+        })
+        .await
+    }
+
+    async fn release(&self, holder: &str) -> anyhow::Result<()> {
+        self.update(|state| {
+            if state.holder != holder {
+                return None;
+            }
+            Some(LeaseState {
+                name: self.name.clone(),
+                ..Default::default()
+            })
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LeaseInput {
+    pub name: String,
+    pub holder: String,
+    pub ttl: u64, // milliseconds
+}
+
+// HttpLease asks a lease service such as taskbase, it POSTs LeaseInput to
+// "{endpoint}/acquire" and "{endpoint}/release" and gets the LeaseState.
+pub struct HttpLease {
+    name: String,
+    client: Client,
+    endpoint: reqwest::Url,
+}
+
+impl HttpLease {
+    pub fn new(name: &str, endpoint: &str) -> anyhow::Result<Self> {
+        let mut endpoint = reqwest::Url::parse(endpoint)?;
+        if !endpoint.path().ends_with('/') {
+            endpoint.set_path(&format!("{}/", endpoint.path()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            client: super::new_client()?,
+            endpoint,
+        })
+    }
+
+    async fn call(&self, path: &str, holder: &str, ttl: Duration) -> anyhow::Result<LeaseState> {
+        let ctx = ReqContext::new(&uuid::Uuid::new_v4().to_string(), xid::Id::default(), 0);
+        super::send(
+            &self.client,
+            Encoding::Identity,
+            PackObject::Cbor(()),
+            Method::POST,
+            self.endpoint.join(path)?,
+            &ctx,
+            Some(&LeaseInput {
+                name: self.name.clone(),
+                holder: holder.to_string(),
+                ttl: ttl.as_millis() as u64,
+            }),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Lease for HttpLease {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    async fn acquire(&self, holder: &str, ttl: Duration) -> anyhow::Result<bool> {
+        let state = self.call("acquire", holder, ttl).await?;
+        Ok(state.holder == holder)
+    }
+
+    async fn release(&self, holder: &str) -> anyhow::Result<()> {
+        self.call("release", holder, Duration::ZERO).await?;
+        Ok(())
+    }
+}
+
+// Leader tells whether this replica runs the scheduled jobs. Without a lease
+// every replica is the leader.
+pub struct Leader {
+    pub holder: String,
+    lease: Option<Box<dyn Lease>>,
+    ttl: Duration,
+    is_leader: AtomicBool,
+    expires_at: Mutex<Option<Instant>>, // of the lease held by this replica
+}
+
+impl Leader {
+    pub fn new(cfg: &conf::Lease) -> anyhow::Result<Self> {
+        let lease: Option<Box<dyn Lease>> = match cfg.kind.as_str() {
+            "" => None,
+            "file" => Some(Box::new(FileLease::new(&cfg.name, &cfg.path)?)),
+            "http" => Some(Box::new(HttpLease::new(&cfg.name, &cfg.endpoint)?)),
+            kind => anyhow::bail!("invalid lease kind {:?}", kind),
+        };
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+        Ok(Self {
+            holder: format!("{}:{}", host, std::process::id()),
+            is_leader: AtomicBool::new(lease.is_none()),
+            lease,
+            ttl: Duration::from_secs(cfg.ttl.max(3)),
+            expires_at: Mutex::new(None),
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    // acquires or renews the lease. On a failure, e.g. the lease file is contended,
+    // the leadership is kept until the lease expires since no other replica can take
+    // it before.
+    pub async fn renew(&self) -> bool {
+        let lease = match &self.lease {
+            None => return true,
+            Some(lease) => lease,
+        };
+        let start = Instant::now();
+        let is_leader = match lease.acquire(&self.holder, self.ttl).await {
+            Ok(held) => {
+                *self.expires_at.lock().unwrap() = held.then(|| start + self.ttl);
+                held
+            }
+            Err(err) => {
+                let held = self
+                    .expires_at
+                    .lock()
+                    .unwrap()
+                    .map_or(false, |at| at > Instant::now());
+                log::warn!(target: "job",
+                    action = "renew_lease",
+                    lease = lease.name(),
+                    holder = &self.holder,
+                    leader = held,
+                    error = err.to_string();
+                    "",
+                );
+                held
+            }
+        };
+        if self.is_leader.swap(is_leader, Ordering::SeqCst) != is_leader {
+            log::info!(target: "job",
+                action = "leadership",
+                lease = lease.name(),
+                holder = &self.holder,
+                leader = is_leader;
+                "",
+            );
+        }
+        is_leader
+    }
+
+    pub async fn release(&self) {
+        if let Some(lease) = &self.lease {
+            self.is_leader.store(false, Ordering::SeqCst);
+            *self.expires_at.lock().unwrap() = None;
+            if let Err(err) = lease.release(&self.holder).await {
+                log::warn!(target: "job",
+                    action = "release_lease",
+                    lease = lease.name(),
+                    holder = &self.holder,
+                    error = err.to_string();
+                    "",
+                );
+            }
+        }
+    }
+
+    // renews the lease at a third of its ttl.
+    pub fn spawn_renew(self: Arc<Self>) {
+        if self.lease.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                self.renew().await;
+                sleep(self.ttl / 3).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing, Router};
+    use axum_web::erring::SuccessResponse;
+
+    use super::super::testing::serve;
+
+    #[tokio::test]
+    async fn file_lease() {
+        let dir = std::env::temp_dir().join(format!("rpa-lease-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("leader.lock");
+        let lease = FileLease::new("rpa", path.to_str().unwrap()).unwrap();
+        let ttl = Duration::from_secs(60);
+
+        assert!(lease.acquire("a", ttl).await.unwrap());
+        assert!(lease.acquire("a", ttl).await.unwrap());
+        assert!(!lease.acquire("b", ttl).await.unwrap());
+        lease.release("b").await.unwrap();
+        assert!(!lease.acquire("b", ttl).await.unwrap());
+        lease.release("a").await.unwrap();
+        assert!(lease.acquire("b", Duration::ZERO).await.unwrap());
+        // expired
+        assert!(lease.acquire("a", ttl).await.unwrap());
+
+        // changed by another replica
+        fs::write(&lease.guard, b"").unwrap();
+        assert!(lease.acquire("a", ttl).await.is_err());
+
+        // a fresh guard is not taken over
+        lease.remove_stale_guard();
+        assert!(lease.guard.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn contended_file_lease() {
+        let dir = std::env::temp_dir().join(format!("rpa-lease-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("leader.lock");
+        let leader = Leader::new(&conf::Lease {
+            kind: "file".to_string(),
+            path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let guard = FileLease::new("rpa", path.to_str().unwrap()).unwrap().guard;
+        assert!(leader.renew().await);
+
+        // the guard is released by the other replica while waiting
+        fs::write(&guard, b"").unwrap();
+        let other = guard.clone();
+        tokio::spawn(async move {
+            sleep(GUARD_RETRY_INTERVAL).await;
+            fs::remove_file(other).unwrap();
+        });
+        assert!(leader.renew().await);
+
+        // held by the other replica, the leadership is kept until the lease expires
+        fs::write(&guard, b"").unwrap();
+        assert!(leader.renew().await);
+        assert!(leader.is_leader());
+        *leader.expires_at.lock().unwrap() = Some(Instant::now());
+        assert!(!leader.renew().await);
+        assert!(!leader.is_leader());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    async fn lease_stub(
+        State(state): State<Arc<Mutex<LeaseState>>>,
+        to: PackObject<()>,
+        input: PackObject<LeaseInput>,
+    ) -> PackObject<SuccessResponse<LeaseState>> {
+        let input = input.unwrap();
+        let mut state = state.lock().unwrap();
+        if state.holder.is_empty() || state.holder == input.holder {
+            state.holder = input.holder;
+        }
+        to.with(SuccessResponse::new(state.clone()))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_leader() {
+        let state = Arc::new(Mutex::new(LeaseState::default()));
+        let app = Router::new()
+            .route("/v1/lease/acquire", routing::post(lease_stub))
+            .with_state(state.clone());
+        let addr = serve(app);
+
+        let leader = Leader::new(&conf::Lease {
+            kind: "http".to_string(),
+            endpoint: format!("http://{}/v1/lease", addr),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!leader.is_leader());
+        assert!(leader.renew().await);
+        assert!(leader.is_leader());
+        assert_eq!(state.lock().unwrap().holder, leader.holder);
+
+        state.lock().unwrap().holder = "other".to_string();
+        assert!(!leader.renew().await);
+        assert!(!leader.is_leader());
+
+        // release is not routed, the leadership is given up anyway
+        state.lock().unwrap().holder = "".to_string();
+        assert!(leader.renew().await);
+        leader.release().await;
+        assert!(!leader.is_leader());
+
+        let leader = Leader::new(&conf::Lease::default()).unwrap();
+        assert!(leader.is_leader());
+    }
+}
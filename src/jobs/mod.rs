@@ -1,24 +1,85 @@
 use apalis_core::context::JobContext;
-use libflate::gzip::Encoder;
 use reqwest::{header, Client, Method};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    io::Write,
+    collections::{BTreeMap, BTreeSet},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
+use tokio::time::timeout;
 
-use crate::conf;
+use crate::{
+    audit::{self, AuditLog},
+    conf,
+    storage::Storage,
+    telemetry::{Span, SpanKind, Tracer},
+};
 use axum_web::{
-    context::unix_ms,
-    erring::SuccessResponse,
-    object::{cbor_from_slice, cbor_to_vec, PackObject},
+    context::{unix_ms, ReqContext},
+    encoding::Encoding,
+    erring::{HTTPError, SuccessResponse},
+    object::{cbor_from_slice, cbor_to_vec, get_content_type, PackObject},
 };
 
+pub mod breaker;
+pub mod cancel;
+pub mod delay;
+pub mod digest;
+pub mod inbox;
+pub mod lease;
+pub mod moderation;
+pub mod publish;
+pub mod ratelimit;
+pub mod reaper;
+pub mod registry;
+pub mod review;
+pub mod shard;
+#[cfg(test)]
+pub mod testing;
+pub mod translation;
+pub mod webhook;
+
+use breaker::{Breakers, Unavailable};
+use cancel::{Cancel, Cancelled, Scope};
+use delay::DelayQueue;
+use digest::Digest;
+use inbox::Inbox;
+use moderation::Moderator;
+use ratelimit::{Limiter, RateLimited};
+use reaper::Reaper;
+use review::{Decision, Rules};
+use shard::Shard;
+use translation::Translation;
+use webhook::Webhook;
+
 const JARVIS: &str = "0000000000000jarvis0";
 const COMPRESS_MIN_LENGTH: usize = 512;
+const ACCEPT_ENCODING: &str = "zstd, gzip";
+const ACCEPT_CBOR: &str = "application/cbor, application/json;q=0.9";
+const ACCEPT_JSON: &str = "application/json, application/cbor;q=0.9";
+const REVIEW_KIND: &str = "review.publication";
+pub const PUBLISH_KIND: &str = "publish.scheduled";
+// names of the jobs
+pub const REVIEW_JOB: &str = "publication_review";
+pub const PUBLISH_JOB: &str = "scheduled_publish";
+pub const REAPER_JOB: &str = "stale_reaper";
+pub const TRANSLATION_JOB: &str = "publication_translation";
+pub const DIGEST_JOB: &str = "reviewer_digest";
+pub const OUTBOX_JOB: &str = "webhook_outbox";
+const PAGE_SIZE: u16 = 1000;
+// storage keys of the review job
+const REVIEW_LAST_SUCCESS: &str = "review:last_success";
+const REVIEW_FAILURES: &str = "review:failures:";
+const REVIEW_DELAYED: &str = "review:delayed:";
+// the publication a task was escalated for, until the task is acked
+const REVIEW_ESCALATED: &str = "review:escalated:";
+// batch routes of the upstreams
+const BATCH_UPDATE_STATUS: &str = "/v1/publication/batch_update_status";
+const BATCH_ACK_TASK: &str = "/v1/task/batch_ack";
+const BATCH_DELETE_NOTIFICATION: &str = "/v1/notification/batch_delete";
 static X_REQUEST_ID: header::HeaderName = header::HeaderName::from_static("x-request-id");
+static TRACEPARENT: header::HeaderName = header::HeaderName::from_static("traceparent");
 static APP_USER_AGENT: &str = concat!(
     "reqwest ",
     env!("CARGO_PKG_NAME"),
@@ -26,101 +87,469 @@ static APP_USER_AGENT: &str = concat!(
     env!("CARGO_PKG_VERSION"),
 );
 
-pub struct RPA {
+// Upstream is a service that the RPA calls.
+pub struct Upstream {
+    pub name: String,
+    pub base: reqwest::Url,
+    key: String, // origin of the base URL, keys the circuit breaker
+    limiter: Limiter,
+    max_retries: u32,
+    encoding: Encoding,     // for request bodies
+    format: PackObject<()>, // for request bodies
+    batch_size: usize,
+    unbatched: Mutex<BTreeSet<String>>, // batch routes that the upstream does not support
     client: Client,
-    taskbase: reqwest::Url,
-    writing: reqwest::Url,
+}
+
+impl Upstream {
+    pub fn new(name: &str, base: &str, cfg: Option<&conf::Upstream>) -> anyhow::Result<Self> {
+        let base = reqwest::Url::parse(base)?;
+        let default_cfg = conf::Upstream::default();
+        let cfg = cfg.unwrap_or(&default_cfg);
+        Ok(Self {
+            name: name.to_string(),
+            key: base.origin().ascii_serialization(),
+            base,
+            limiter: Limiter::new(cfg.rate_limit, cfg.burst),
+            max_retries: cfg.max_retries,
+            encoding: cfg.encoding,
+            format: match cfg.format {
+                conf::Format::Cbor => PackObject::Cbor(()),
+                conf::Format::Json => PackObject::Json(()),
+            },
+            batch_size: cfg.batch_size.max(1),
+            unbatched: Mutex::new(BTreeSet::new()),
+            client: build_client(cfg)?,
+        })
+    }
+
+    pub fn join(&self, path: &str) -> anyhow::Result<reqwest::Url> {
+        Ok(self.base.join(path)?)
+    }
+
+    // returns false if batch calls are disabled or the route is not supported.
+    fn batching(&self, path: &str) -> bool {
+        self.batch_size > 1 && !self.unbatched.lock().unwrap().contains(path)
+    }
+}
+
+pub struct RPA {
+    breakers: Arc<Breakers>,
+    tracer: Arc<Tracer>,
+    audit: Arc<AuditLog>,
+    storage: Arc<dyn Storage>,
+    inbox: Arc<Inbox>,
+    pub delayed: Arc<DelayQueue>,
+    taskbase: Upstream,
+    writing: Upstream,
     system_user: PackObject<xid::Id>,
+    reviewers: Vec<PackObject<xid::Id>>,
+    grace_period: i64, // milliseconds
+    max_failures: i64,
+    shard: Shard,
+    reaper: Reaper,
+    translation: Translation,
+    digest: Digest,
+    webhook: Webhook,
+    rules: Rules,
+    moderators: Vec<Box<dyn Moderator>>,
+    shutdown: Cancel,
+    run_timeout: Duration,
+    item_timeout: Duration,
+    moderation_timeout: Duration,
 }
 
 impl RPA {
-    pub fn new(cfg: conf::Conf) -> Self {
-        let mut headers: header::HeaderMap<header::HeaderValue> =
-            header::HeaderMap::with_capacity(2);
-        headers.insert(header::ACCEPT, "application/cbor".parse().unwrap());
-        headers.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
-        headers.insert("x-auth-user", JARVIS.parse().unwrap());
-        headers.insert("x-auth-user-rating", "127".parse().unwrap());
-
-        let client = reqwest::Client::builder()
-            .use_rustls_tls()
-            .https_only(false)
-            .http2_keep_alive_interval(Some(Duration::from_secs(25)))
-            .http2_keep_alive_timeout(Duration::from_secs(15))
-            .http2_keep_alive_while_idle(true)
-            .connect_timeout(Duration::from_secs(5))
-            .timeout(Duration::from_secs(60))
-            .gzip(true)
-            .user_agent(APP_USER_AGENT)
-            .default_headers(headers)
-            .build()
-            .unwrap();
-
-        let taskbase = reqwest::Url::parse(&cfg.base.taskbase).unwrap();
-        let writing = reqwest::Url::parse(&cfg.base.writing).unwrap();
+    pub fn new(cfg: conf::Conf, state: &conf::AppState) -> Self {
+        let taskbase = Upstream::new(
+            "taskbase",
+            &cfg.base.taskbase,
+            cfg.upstreams.get("taskbase"),
+        )
+        .unwrap_or_else(|err| panic!("invalid taskbase upstream config: {}", err));
+        let writing = Upstream::new("writing", &cfg.base.writing, cfg.upstreams.get("writing"))
+            .unwrap_or_else(|err| panic!("invalid writing upstream config: {}", err));
+        state.breakers.register(&taskbase.key);
+        state.breakers.register(&writing.key);
+        // ids sent to taskbase are packed in its wire format.
+        let reviewers: Vec<PackObject<xid::Id>> = cfg
+            .review
+            .reviewers
+            .iter()
+            .map(|uid| taskbase.format.with(xid::Id::from_str(uid).unwrap()))
+            .collect();
+        let system_user = taskbase.format.with(xid::Id::from_str(JARVIS).unwrap());
+        let rules =
+            Rules::new(&cfg.review).unwrap_or_else(|err| panic!("invalid review rules: {}", err));
+        let shard =
+            Shard::new(&cfg.shard).unwrap_or_else(|err| panic!("invalid shard config: {}", err));
+        if shard.count > 1 && !cfg.lease.kind.is_empty() {
+            panic!("invalid shard config: shards need every replica to run the jobs, disable the lease");
+        }
+        let reaper = Reaper::new(&cfg.reaper, &taskbase.format)
+            .unwrap_or_else(|err| panic!("invalid reaper config: {}", err));
+        let translation = Translation::new(&cfg.translation, &taskbase.format)
+            .unwrap_or_else(|err| panic!("invalid translation config: {}", err));
+        let digest =
+            Digest::new(&cfg.digest).unwrap_or_else(|err| panic!("invalid digest config: {}", err));
+        let webhook = Webhook::new(&cfg.webhook)
+            .unwrap_or_else(|err| panic!("invalid webhook config: {}", err));
+        // events in an in-memory outbox are lost on restart.
+        if webhook.enabled() && cfg.storage.path.is_empty() {
+            panic!("invalid webhook config: the outbox needs a [storage] path");
+        }
+        let moderators = moderation::new_moderators(&cfg.moderation)
+            .unwrap_or_else(|err| panic!("invalid moderation config: {}", err));
+        // moderators escalate when they fail or time out.
+        if reviewers.is_empty() && (rules.escalates() || !moderators.is_empty()) {
+            panic!("invalid review config: escalation needs reviewers");
+        }
 
         Self {
-            client,
+            breakers: state.breakers.clone(),
+            tracer: state.tracer.clone(),
+            audit: state.audit.clone(),
+            storage: state.storage.clone(),
+            inbox: state.inbox.clone(),
+            delayed: Arc::new(DelayQueue::new(
+                state.storage.clone(),
+                REVIEW_DELAYED,
+                cfg.review.max_delayed,
+            )),
             taskbase,
             writing,
-            system_user: PackObject::Cbor(xid::Id::from_str(JARVIS).unwrap()),
+            system_user,
+            reviewers,
+            grace_period: cfg.review.grace_period as i64 * 1000,
+            max_failures: cfg.review.max_failures.max(1) as i64,
+            shard,
+            reaper,
+            translation,
+            digest,
+            webhook,
+            rules,
+            moderators,
+            moderation_timeout: Duration::from_secs(cfg.moderation.timeout),
+            shutdown: state.shutdown.clone(),
+            run_timeout: Duration::from_secs(cfg.jobs.run_timeout),
+            item_timeout: Duration::from_secs(cfg.jobs.item_timeout),
         }
     }
 
+    // bounds a run by the deadlines, it is cancelled on shutdown or by the run's cancel.
+    pub fn run_timeout(&self) -> Duration {
+        self.run_timeout
+    }
+
+    pub fn scope(&self, run: Option<Cancel>) -> Scope {
+        let mut cancels = vec![self.shutdown.clone()];
+        cancels.extend(run);
+        Scope::new(cancels, self.run_timeout, self.item_timeout)
+    }
+
+    // runs the job, returns the counts of items handled by the run.
     pub async fn execute(
         &self,
         ctx: &JobContext,
-        _state: Arc<conf::AppState>,
-    ) -> anyhow::Result<()> {
-        self.publication_review(ctx.id().inner().0).await
+        job: &str,
+        cancel: Cancel,
+    ) -> anyhow::Result<BTreeMap<String, u64>> {
+        // every run starts a new trace, the job id is the request id of upstream calls.
+        let rid = uuid::Uuid::from_u128(ctx.id().inner().0).to_string();
+        let ctx = ReqContext::new(&rid, *self.system_user, i8::MAX);
+        let mut span = Span::new(job, SpanKind::Internal, &ctx.trace);
+        span.attr("rid", &ctx.rid).attr("action", "execute");
+        let scope = self.scope(Some(cancel));
+        let res = match job {
+            REVIEW_JOB => self
+                .publication_review(&ctx, &scope)
+                .await
+                .map(|_| BTreeMap::new()),
+            PUBLISH_JOB => self
+                .scheduled_publish(&ctx, &scope)
+                .await
+                .map(|_| BTreeMap::new()),
+            REAPER_JOB => self.reap_stale(&ctx, &scope).await,
+            TRANSLATION_JOB => self.translate(&ctx, &scope).await,
+            DIGEST_JOB => self.send_digests(&ctx, &scope).await,
+            OUTBOX_JOB => self.deliver_outbox(&ctx, &scope).await,
+            _ => Err(anyhow::anyhow!("unknown job {:?}", job)),
+        };
+        if let Err(err) = &res {
+            span.error(err);
+        }
+        self.tracer.finish(span);
+        res
     }
 
     async fn request<IN: Serialize, OUT: DeserializeOwned>(
         &self,
+        upstream: &Upstream,
         method: Method,
         url: reqwest::Url,
-        rid: &str,
+        ctx: &ReqContext,
         body: Option<&IN>,
     ) -> anyhow::Result<OUT> {
-        let req = self
-            .client
-            .request(method, url)
-            .header(header::ACCEPT_ENCODING, "gzip")
-            .header(&X_REQUEST_ID, rid);
-
-        let res = match body {
-            None => req.send().await?,
-            Some(body) => {
-                let data = cbor_to_vec(body)?;
-                if data.len() >= COMPRESS_MIN_LENGTH {
-                    let mut encoder = Encoder::new(Vec::new())?;
-                    encoder.write_all(&data)?;
-                    let data = encoder.finish().into_result()?;
-
-                    req.header("content-encoding", "gzip")
-                        .header(header::CONTENT_TYPE, "application/cbor")
-                        .body(data)
-                        .send()
-                        .await?
-                } else {
-                    req.header(header::CONTENT_TYPE, "application/cbor")
-                        .body(data)
-                        .send()
-                        .await?
+        let res = self
+            .request_response(upstream, method, url, ctx, body)
+            .await?;
+        Ok(res.result)
+    }
+
+    // returns the whole response, with the next page token of a list.
+    async fn request_response<IN: Serialize, OUT: DeserializeOwned>(
+        &self,
+        upstream: &Upstream,
+        method: Method,
+        url: reqwest::Url,
+        ctx: &ReqContext,
+        body: Option<&IN>,
+    ) -> anyhow::Result<SuccessResponse<OUT>> {
+        // every call is a child span of the caller.
+        let ctx = &ctx.child();
+        let mut span = Span::new(
+            &format!("{} {}", method, upstream.name),
+            SpanKind::Client,
+            &ctx.trace,
+        );
+        span.attr("rid", &ctx.rid)
+            .attr("action", "request")
+            .attr("upstream", &upstream.name)
+            .attr("http.method", method.as_str())
+            .attr("http.url", url.as_str());
+        let res = self
+            .request_with_retry(upstream, method, url, ctx, body)
+            .await;
+        if let Err(err) = &res {
+            span.error(err);
+        }
+        self.tracer.finish(span);
+        res
+    }
+
+    async fn request_with_retry<IN: Serialize, OUT: DeserializeOwned>(
+        &self,
+        upstream: &Upstream,
+        method: Method,
+        url: reqwest::Url,
+        ctx: &ReqContext,
+        body: Option<&IN>,
+    ) -> anyhow::Result<SuccessResponse<OUT>> {
+        let mut retries = 0;
+        loop {
+            upstream.limiter.acquire().await;
+            let permit = self.breakers.acquire(&upstream.key)?;
+            let err = match send_response(
+                &upstream.client,
+                upstream.encoding,
+                upstream.format.clone(),
+                method.clone(),
+                url.clone(),
+                ctx,
+                body,
+            )
+            .await
+            {
+                Ok(output) => {
+                    permit.record(true);
+                    return Ok(output);
+                }
+                Err(err) => err,
+            };
+
+            // the upstream is up if it responded with a client error or 501 Not Implemented.
+            let failed = match err.downcast_ref::<HTTPError>() {
+                Some(err) => err.code >= 500 && err.code != 501,
+                None => err.downcast_ref::<reqwest::Error>().is_some(),
+            };
+            // a failure counts against the item until the circuit opens, so that an
+            // item the upstream always fails on does not stop every run.
+            if permit.record(!failed) == breaker::State::Open {
+                return Err(Unavailable {
+                    upstream: upstream.key.clone(),
+                    reason: err.to_string(),
                 }
+                .into());
+            }
+            if failed {
+                return Err(err);
             }
-        };
 
-        let status = res.status().as_u16();
-        if status >= 204 {
-            let text = res.text().await?;
-            anyhow::bail!("{}: {}", status, text);
+            match err.downcast_ref::<RateLimited>() {
+                Some(limited) if retries < upstream.max_retries => {
+                    retries += 1;
+                    log::warn!(target: "job",
+                        action = "rate_limited",
+                        rid = &ctx.rid,
+                        span = &ctx.trace.span_id,
+                        upstream = &upstream.name,
+                        retries = retries,
+                        retry_after = limited.retry_after.as_millis() as u64;
+                        "",
+                    );
+                    upstream.limiter.pause(limited.retry_after);
+                }
+                // the item is fine, the run stops and keeps it for the next run.
+                Some(limited) => {
+                    return Err(Unavailable {
+                        upstream: upstream.key.clone(),
+                        reason: format!("rate limited after {} retries, {}", retries, limited),
+                    }
+                    .into())
+                }
+                None => return Err(err),
+            }
         }
+    }
+}
+
+// the client of the internal services, it identifies as the RPA user.
+pub fn new_client() -> anyhow::Result<Client> {
+    build_client(&conf::Upstream::default())
+}
+
+// the client of the third-party endpoints, e.g. a moderation service, it does not
+// send the identity headers of the RPA user.
+pub fn new_external_client() -> anyhow::Result<Client> {
+    let cfg = conf::Upstream::default();
+    Ok(reqwest::Client::builder()
+        .use_rustls_tls()
+        .connect_timeout(Duration::from_secs(cfg.connect_timeout))
+        .timeout(Duration::from_secs(cfg.timeout))
+        .gzip(true)
+        .user_agent(APP_USER_AGENT)
+        .build()?)
+}
+
+// builds a client with the connection settings of the upstream config.
+pub fn build_client(cfg: &conf::Upstream) -> anyhow::Result<Client> {
+    let mut headers: header::HeaderMap<header::HeaderValue> =
+        header::HeaderMap::with_capacity(4 + cfg.headers.len());
+    headers.insert(header::ACCEPT, ACCEPT_CBOR.parse().unwrap());
+    headers.insert(header::ACCEPT_ENCODING, ACCEPT_ENCODING.parse().unwrap());
+    headers.insert("x-auth-user", JARVIS.parse().unwrap());
+    headers.insert("x-auth-user-rating", "127".parse().unwrap());
+    for (name, value) in &cfg.headers {
+        let name = header::HeaderName::from_str(name)
+            .map_err(|err| anyhow::anyhow!("invalid header {:?}, {}", name, err))?;
+        // the built-in headers identify the RPA user and the formats it accepts.
+        if headers.contains_key(&name) {
+            anyhow::bail!("header {:?} is reserved", name.as_str());
+        }
+        let value = header::HeaderValue::from_str(value)
+            .map_err(|err| anyhow::anyhow!("invalid header {:?} value, {}", name, err))?;
+        headers.insert(name, value);
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .https_only(false)
+        .http2_keep_alive_interval(Some(Duration::from_secs(cfg.http2_keep_alive_interval)))
+        .http2_keep_alive_timeout(Duration::from_secs(cfg.http2_keep_alive_timeout))
+        .http2_keep_alive_while_idle(true)
+        .connect_timeout(Duration::from_secs(cfg.connect_timeout))
+        .timeout(Duration::from_secs(cfg.timeout))
+        .pool_idle_timeout(Duration::from_secs(cfg.pool_idle_timeout))
+        .pool_max_idle_per_host(cfg.pool_max_idle)
+        .gzip(true)
+        .user_agent(APP_USER_AGENT)
+        .default_headers(headers);
+    if cfg.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if !cfg.proxy.is_empty() {
+        builder = builder.proxy(
+            reqwest::Proxy::all(&cfg.proxy)
+                .map_err(|err| anyhow::anyhow!("invalid proxy {:?}, {}", cfg.proxy, err))?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+// sends the body in the format, compressed with the encoding if it is large enough,
+// and decodes the result by the response content type. The request id and the
+// trace context of ctx are propagated to the upstream.
+pub async fn send<IN: Serialize, OUT: DeserializeOwned>(
+    client: &Client,
+    encoding: Encoding,
+    format: PackObject<()>,
+    method: Method,
+    url: reqwest::Url,
+    ctx: &ReqContext,
+    body: Option<&IN>,
+) -> anyhow::Result<OUT> {
+    let res = send_response(client, encoding, format, method, url, ctx, body).await?;
+    Ok(res.result)
+}
+
+pub async fn send_response<IN: Serialize, OUT: DeserializeOwned>(
+    client: &Client,
+    encoding: Encoding,
+    format: PackObject<()>,
+    method: Method,
+    url: reqwest::Url,
+    ctx: &ReqContext,
+    body: Option<&IN>,
+) -> anyhow::Result<SuccessResponse<OUT>> {
+    let (accept, content_type) = match format {
+        PackObject::Cbor(_) => (ACCEPT_CBOR, "application/cbor"),
+        PackObject::Json(_) => (ACCEPT_JSON, "application/json"),
+    };
+    let req = client
+        .request(method, url)
+        .header(header::ACCEPT, accept)
+        .header(header::ACCEPT_ENCODING, ACCEPT_ENCODING)
+        .header(&X_REQUEST_ID, &ctx.rid)
+        .header(&TRACEPARENT, ctx.trace.traceparent());
+
+    let res = match body {
+        None => req.send().await?,
+        Some(body) => {
+            let data = match format {
+                PackObject::Cbor(_) => cbor_to_vec(body)?,
+                PackObject::Json(_) => serde_json::to_vec(body)?,
+            };
+            if data.len() >= COMPRESS_MIN_LENGTH && !encoding.identity() {
+                let data = encoding.encode_all(&data[..])?;
+                req.header(header::CONTENT_ENCODING, encoding.header_value())
+                    .header(header::CONTENT_TYPE, content_type)
+                    .body(data)
+                    .send()
+                    .await?
+            } else {
+                req.header(header::CONTENT_TYPE, content_type)
+                    .body(data)
+                    .send()
+                    .await?
+            }
+        }
+    };
+
+    let status = res.status().as_u16();
+    let headers = res.headers().clone();
+    let mut body = res.bytes().await?;
+    // gzip is decoded by reqwest, zstd is not.
+    let enc = Encoding::from_header_value(headers.get(header::CONTENT_ENCODING));
+    if !enc.identity() {
+        body = enc.decode_all(&body[..])?.into();
+    }
 
-        let body = res.bytes().await?;
-        let output: SuccessResponse<OUT> = cbor_from_slice(&body)?;
-        Ok(output.result)
+    if status == 429 {
+        let text = String::from_utf8_lossy(&body).to_string();
+        return Err(RateLimited::new(&headers, text).into());
+    }
+    if status >= 204 {
+        let text = String::from_utf8_lossy(&body).to_string();
+        return Err(HTTPError::new(status, text).into());
     }
+
+    // fall back to the request format if the content type is unknown.
+    let output: SuccessResponse<OUT> = match get_content_type(&headers).unwrap_or(format) {
+        PackObject::Cbor(_) => cbor_from_slice(&body)?,
+        PackObject::Json(_) => serde_json::from_slice(&body)
+            .map_err(|err| HTTPError::new(400, format!("Invalid JSON bytes, {}", err)))?,
+    };
+    Ok(output)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -132,7 +561,7 @@ pub struct Pagination {
     pub fields: Option<Vec<String>>,
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct NotificationOutput {
     pub sender: PackObject<xid::Id>,
     pub tid: PackObject<xid::Id>,
@@ -141,6 +570,8 @@ pub struct NotificationOutput {
     pub ack_status: i8,
     pub kind: String,
     pub payload: PackObject<Vec<u8>>,
+    #[serde(default)]
+    pub created_at: i64, // unix milliseconds
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -152,6 +583,27 @@ pub struct AckTaskInput {
     pub message: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct BatchInput<'a, T: Serialize> {
+    pub items: &'a [&'a T],
+}
+
+// the result of an item of a batch call, in the order of the items.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BatchItemOutput {
+    #[serde(default)]
+    pub error: Option<String>, // None if the item succeeded
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateTaskInput {
+    pub uid: PackObject<xid::Id>,
+    pub gid: PackObject<xid::Id>,
+    pub kind: String,
+    pub to: Vec<PackObject<xid::Id>>,
+    pub payload: PackObject<Vec<u8>>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DeleteTaskInput {
     pub uid: PackObject<xid::Id>,
@@ -178,128 +630,838 @@ pub struct PublicationOutput {
     pub content_length: usize,
 }
 
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PublicationContentOutput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: String,
+    pub version: i16,
+    pub content: PackObject<Vec<u8>>,
+}
+
+// TodoItem keeps what is needed to finish a reviewed todo item.
+struct TodoItem {
+    ctx: ReqContext,
+    action: &'static str,
+    start: u64, // milliseconds since the run start
+    sender: PackObject<xid::Id>,
+    tid: PackObject<xid::Id>,
+    failures_key: String,
+    publ: Option<PublicationInput>, // from the payload, for the audit log
+}
+
+// Changes of a reviewed todo item, they are applied in batches.
+struct Changes {
+    status: Option<(PublicationOutput, audit::Entry)>,
+    ack: (AckTaskInput, audit::Entry),
+}
+
+fn escalated_key(tid: &PackObject<xid::Id>) -> String {
+    format!("{}{}", REVIEW_ESCALATED, **tid)
+}
+
+// copies the error of a batch call for an item of the batch.
+fn batch_error(err: &anyhow::Error) -> anyhow::Error {
+    match err.downcast_ref::<Unavailable>() {
+        Some(err) => Unavailable {
+            upstream: err.upstream.clone(),
+            reason: err.reason.clone(),
+        }
+        .into(),
+        None => anyhow::anyhow!("batch call failed, {}", err),
+    }
+}
+
 impl RPA {
-    async fn publication_review(&self, jid: u128) -> anyhow::Result<()> {
-        let jid = uuid::Uuid::from_u128(jid).to_string();
-        let ts = unix_ms() as i64 - 8 * 60 * 1000;
+    async fn publication_review(&self, ctx: &ReqContext, scope: &Scope) -> anyhow::Result<()> {
+        let ts = unix_ms() as i64 - self.grace_period;
         let start = Instant::now();
-        let todo = self.list_todo(&jid).await?;
+        for m in &self.moderators {
+            for err in m.reload() {
+                log::warn!(target: "job",
+                    action = "reload_moderator",
+                    rid = &ctx.rid,
+                    moderator = m.name(),
+                    error = err.to_string();
+                    "keep previous config",
+                );
+            }
+        }
+        let last_success: u64 = self
+            .storage
+            .get_object(REVIEW_LAST_SUCCESS)
+            .unwrap_or_default()
+            .unwrap_or_default();
+        let mut todo = self.list_todo(ctx, None).await?;
+        let todo_keys: BTreeSet<String> = todo
+            .iter()
+            .map(|item| format!("{}{}", REVIEW_FAILURES, *item.tid))
+            .collect();
+        let total = todo.len();
+        let todo_tids: BTreeSet<String> = todo.iter().map(|item| item.tid.to_string()).collect();
+        // delayed items that are gone from every page of todo are dropped.
+        self.delayed.retain(|tid| todo_tids.contains(tid));
+        // delayed items are handled when they are due, scheduled ones by the publish job.
+        todo.retain(|item| {
+            item.kind != PUBLISH_KIND
+                && self.shard.owns(&item.tid)
+                && !self.delayed.contains(&item.tid.to_string())
+        });
         log::info!(target: "job",
             action = "list_todo",
-            rid = &jid,
+            rid = &ctx.rid,
+            trace = &ctx.trace.trace_id,
+            span = &ctx.trace.span_id,
+            last_success = last_success,
+            shard = self.shard.index,
+            total = total,
             todo = todo.len();
             "start",
         );
 
+        // reviewed changes are applied in batches, their tasks stay claimed until then.
+        let flush_size = self.taskbase.batch_size.max(self.writing.batch_size);
+        let mut reviewed: Vec<(TodoItem, Changes)> = Vec::new();
+        let mut available = true;
+        let mut stopped = None;
         for item in todo {
-            let item_start = start.elapsed().as_millis() as u64;
-            let task_uid = item.sender.clone();
-            let task_id = item.tid.clone();
-            let res = self.publication_review_item(&jid, ts, item).await;
-            let elapsed = start.elapsed().as_millis() as u64 - item_start;
+            if let Err(err) = scope.check() {
+                stopped = Some(err);
+                break;
+            }
+            let tid = item.tid.to_string();
+            if !self.inbox.claim(&tid) {
+                // being handled by the webhook
+                continue;
+            }
+            let (todo_item, res) = self.review_todo(ctx, scope, ts, &start, item).await;
             match res {
-                Ok(_) => {
-                    log::info!(target: "job",
-                        action = "publication_review",
-                        rid = &jid,
-                        start = item_start,
-                        elapsed = elapsed;
-                        "finished",
-                    );
-                }
-                Err(err) => {
-                    log::error!(
-                        target: "job",
-                        rid = &jid,
-                        start = item_start,
-                        elapsed = elapsed,
-                        error = err.to_string();
-                        "failed",
-                    );
-                    // clear invalid task
-                    let _ = self
-                        .remove_todo(
-                            &jid,
-                            &DeleteTaskInput {
-                                uid: task_uid,
-                                id: Some(task_id),
-                                status: None,
-                            },
-                        )
-                        .await;
+                Ok(Some(changes)) => reviewed.push((todo_item, changes)),
+                res => {
+                    available = self.finish_todo(&start, &todo_item, res.map(|_| ())).await;
+                    self.inbox.release(&tid);
                 }
             }
+            if available && reviewed.len() >= flush_size {
+                available = self.flush(ctx, &start, &mut reviewed).await;
+            }
+            if !available {
+                break;
+            }
+        }
+        let flushed = self.flush(ctx, &start, &mut reviewed).await;
+        if let Some(err) = stopped {
+            return Err(err);
+        }
+        if !flushed || !available {
+            // keep the rest of todo for the next run.
+            return Ok(());
         }
 
+        // counters and escalations of tasks that are gone, e.g. handled by humans.
+        for key in self.storage.keys(REVIEW_FAILURES).unwrap_or_default() {
+            if !todo_keys.contains(&key) {
+                self.clear_failures(ctx, &key);
+            }
+        }
+        for key in self.storage.keys(REVIEW_ESCALATED).unwrap_or_default() {
+            let tid = &key[REVIEW_ESCALATED.len()..];
+            if !todo_keys.contains(&format!("{}{}", REVIEW_FAILURES, tid)) {
+                self.clear_failures(ctx, &key);
+            }
+        }
+        if let Err(err) = self.storage.set_object(REVIEW_LAST_SUCCESS, &unix_ms()) {
+            log::warn!(target: "job",
+                action = "checkpoint",
+                rid = &ctx.rid,
+                error = err.to_string();
+                "",
+            );
+        }
         Ok(())
     }
 
+    // reviews a todo item as a child span of ctx within the scope, the changes are left to apply.
+    async fn review_todo(
+        &self,
+        ctx: &ReqContext,
+        scope: &Scope,
+        ts: i64,
+        start: &Instant,
+        item: NotificationOutput,
+    ) -> (TodoItem, anyhow::Result<Option<Changes>>) {
+        let todo_item = TodoItem {
+            ctx: ctx.child(),
+            action: "publication_review",
+            start: start.elapsed().as_millis() as u64,
+            sender: item.sender.clone(),
+            tid: item.tid.clone(),
+            failures_key: format!("{}{}", REVIEW_FAILURES, *item.tid),
+            publ: cbor_from_slice(&item.payload).ok(),
+        };
+        let ctx = &todo_item.ctx;
+        let mut span = Span::new("publication_review_item", SpanKind::Internal, &ctx.trace);
+        span.attr("rid", &ctx.rid)
+            .attr("action", "publication_review")
+            .attr("tid", todo_item.tid.to_string());
+        let res = scope.run(self.publication_review_item(ctx, ts, item)).await;
+        if let Err(err) = &res {
+            span.error(err);
+        }
+        self.tracer.finish(span);
+        (todo_item, res)
+    }
+
+    // applies the changes of the reviewed items and finishes them, returns false
+    // if an upstream is unavailable.
+    async fn flush(
+        &self,
+        ctx: &ReqContext,
+        start: &Instant,
+        reviewed: &mut Vec<(TodoItem, Changes)>,
+    ) -> bool {
+        if reviewed.is_empty() {
+            return true;
+        }
+        let reviewed = std::mem::take(reviewed);
+        let results = self.apply(ctx, &reviewed).await;
+        let mut available = true;
+        for ((todo_item, _), res) in reviewed.into_iter().zip(results) {
+            let tid = todo_item.tid.to_string();
+            let acked = res.is_ok();
+            available = self.finish_todo(start, &todo_item, res).await && available;
+            if acked {
+                self.inbox.done(&tid);
+            } else {
+                self.inbox.release(&tid);
+            }
+        }
+        available
+    }
+
+    // logs the result of a todo item and counts its failures, the task is removed
+    // after max_failures. Returns false if an upstream is unavailable.
+    async fn finish_todo(
+        &self,
+        start: &Instant,
+        todo_item: &TodoItem,
+        res: anyhow::Result<()>,
+    ) -> bool {
+        let ctx = &todo_item.ctx;
+        let failures_key = &todo_item.failures_key;
+        let elapsed = start.elapsed().as_millis() as u64 - todo_item.start;
+        match res {
+            Ok(_) => {
+                self.clear_failures(ctx, failures_key);
+                self.clear_failures(ctx, &escalated_key(&todo_item.tid));
+                log::info!(target: "job",
+                    action = todo_item.action,
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id,
+                    start = todo_item.start,
+                    elapsed = elapsed;
+                    "finished",
+                );
+            }
+            Err(err) if err.downcast_ref::<Unavailable>().is_some() => {
+                // keep the task for the next run.
+                log::warn!(target: "job",
+                    action = todo_item.action,
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id,
+                    start = todo_item.start,
+                    elapsed = elapsed,
+                    error = err.to_string();
+                    "stopped",
+                );
+                return false;
+            }
+            Err(err) if err.downcast_ref::<Cancelled>().is_some() => {
+                // leave the task untouched for the next run.
+                log::warn!(target: "job",
+                    action = todo_item.action,
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id,
+                    start = todo_item.start,
+                    elapsed = elapsed,
+                    error = err.to_string();
+                    "stopped",
+                );
+            }
+            Err(err) => {
+                // a failed counter is treated as the last failure.
+                let failures = self
+                    .storage
+                    .incr(failures_key, 1)
+                    .unwrap_or(self.max_failures);
+                log::error!(
+                    target: "job",
+                    action = todo_item.action,
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id,
+                    start = todo_item.start,
+                    elapsed = elapsed,
+                    failures = failures,
+                    error = err.to_string();
+                    "failed",
+                );
+                if failures < self.max_failures {
+                    // keep the task for a retry in the next run.
+                    return true;
+                }
+
+                self.clear_failures(ctx, failures_key);
+                self.clear_failures(ctx, &escalated_key(&todo_item.tid));
+                // clear invalid task
+                let mut audit = audit::Entry::new(ctx, &todo_item.tid);
+                if let Some(publ) = &todo_item.publ {
+                    audit = audit.publication(&publ.gid, &publ.cid, &publ.language, publ.version);
+                }
+                let audit = audit
+                    .reason(&err.to_string())
+                    .action("remove_todo", None, None);
+                self.record_digest(ctx, "failed", &self.reviewers, &audit);
+                let _ = self
+                    .remove_todo(
+                        ctx,
+                        &DeleteTaskInput {
+                            uid: todo_item.sender.clone(),
+                            id: Some(todo_item.tid.clone()),
+                            status: None,
+                        },
+                        audit,
+                    )
+                    .await;
+            }
+        }
+        true
+    }
+
+    // handles a notification pushed by the webhook, its task has been claimed.
+    pub async fn handle_notification(&self, item: NotificationOutput) {
+        let rid = uuid::Uuid::new_v4().to_string();
+        let ctx = ReqContext::new(&rid, *self.system_user, i8::MAX);
+        if !self.shard.owns(&item.tid) {
+            log::info!(target: "job",
+                action = "handle_notification",
+                rid = &ctx.rid,
+                tid = item.tid.to_string(),
+                shard = self.shard.index;
+                "skipped, not in the shard",
+            );
+            return;
+        }
+        let ts = unix_ms() as i64 - self.grace_period;
+        let start = Instant::now();
+        let scope = self.scope(None);
+        let (todo_item, res) = self.review_todo(&ctx, &scope, ts, &start, item).await;
+        match res {
+            Ok(Some(changes)) => {
+                self.flush(&ctx, &start, &mut vec![(todo_item, changes)])
+                    .await;
+            }
+            res => {
+                self.finish_todo(&start, &todo_item, res.map(|_| ())).await;
+            }
+        }
+    }
+
+    fn clear_failures(&self, ctx: &ReqContext, key: &str) {
+        if let Err(err) = self.storage.delete(key) {
+            log::warn!(target: "job",
+                action = "clear_failures",
+                rid = &ctx.rid,
+                key = key,
+                error = err.to_string();
+                "",
+            );
+        }
+    }
+
     async fn publication_review_item(
         &self,
-        jid: &str,
+        ctx: &ReqContext,
         ts: i64,
         item: NotificationOutput,
-    ) -> anyhow::Result<()> {
-        let publ: PublicationInput = cbor_from_slice(&item.payload.unwrap())?;
-        let mut publ = self.get_publication(jid, &publ).await?;
+    ) -> anyhow::Result<Option<Changes>> {
+        let publ: PublicationInput = cbor_from_slice(&item.payload)?;
+        let mut publ = self.get_publication(ctx, &publ).await?;
         if publ.updated_at > ts {
-            return Ok(());
+            let due_at = (publ.updated_at + self.grace_period) as u64;
+            // the item is fine, a failure to delay it leaves it for the next run.
+            match self.delayed.push(due_at, item) {
+                Ok(true) => {}
+                Ok(false) => log::warn!(target: "job",
+                    action = "delay",
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id;
+                    "delay queue is full, left for the next run",
+                ),
+                Err(err) => log::warn!(target: "job",
+                    action = "delay",
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id,
+                    error = err.to_string();
+                    "left for the next run",
+                ),
+            }
+            return Ok(None);
         }
-        if publ.status == 0 {
-            publ.status = 1;
-            let _ = self.set_publication_status(jid, &publ).await?;
-        }
-        self.ack_todo(
-            jid,
-            &AckTaskInput {
-                uid: self.system_user.clone(),
-                tid: item.tid,
-                sender: item.sender,
-                status: 1,
-                message: "Done".to_string(),
-            },
-        )
-        .await?;
-        Ok(())
+
+        let audit = audit::Entry::new(ctx, &item.tid).publication(
+            &publ.gid,
+            &publ.cid,
+            &publ.language,
+            publ.version,
+        );
+        let status = if publ.status == 0 {
+            let mut decision = self.rules.evaluate(&publ);
+            if decision == Decision::Approve && !self.moderators.is_empty() {
+                let content = self
+                    .get_publication_content(
+                        ctx,
+                        &PublicationInput {
+                            gid: publ.gid.clone(),
+                            cid: publ.cid.clone(),
+                            language: publ.language.clone(),
+                            version: publ.version,
+                        },
+                    )
+                    .await?;
+                decision = self.moderate(ctx, &content).await;
+            }
+
+            let old_status = publ.status;
+            let reason = decision.to_string();
+            let new_status = match decision {
+                Decision::Approve => Some(1),
+                Decision::Reject(_) => Some(-1),
+                Decision::Escalate(_) => {
+                    if self.escalate(ctx, &item, &publ).await? {
+                        let audit = audit.clone().reason(&reason);
+                        self.record_digest(ctx, "escalated", &self.reviewers, &audit);
+                        self.emit_decision(ctx, "escalate", &audit);
+                    }
+                    None
+                }
+            };
+            log::info!(target: "job",
+                action = "review_decision",
+                rid = &ctx.rid,
+                span = &ctx.trace.span_id,
+                gid = publ.gid.to_string(),
+                cid = publ.cid.to_string(),
+                language = &publ.language,
+                version = publ.version;
+                "{}", decision,
+            );
+            let status = new_status.map(|status| {
+                let audit = audit.clone().reason(&reason).action(
+                    "set_publication_status",
+                    Some(old_status),
+                    Some(status),
+                );
+                publ.status = status;
+                (publ, audit)
+            });
+            (status, decision.ack_status(), reason)
+        } else {
+            (None, 1, format!("skipped: status {}", publ.status))
+        };
+
+        let (status, ack_status, message) = status;
+        let audit =
+            audit
+                .reason(&message)
+                .action("ack_todo", Some(item.ack_status), Some(ack_status));
+        Ok(Some(Changes {
+            status,
+            ack: (
+                AckTaskInput {
+                    uid: self.system_user.clone(),
+                    tid: item.tid,
+                    sender: item.sender,
+                    status: ack_status,
+                    message,
+                },
+                audit,
+            ),
+        }))
     }
 
-    async fn list_todo(&self, jid: &str) -> anyhow::Result<Vec<NotificationOutput>> {
-        let url = self.taskbase.join("/v1/notification/list")?;
-        let res: Vec<NotificationOutput> = self
+    // asks the moderators in order, the first one that does not approve decides.
+    // A moderator that fails or times out escalates the publication to human reviewers.
+    async fn moderate(&self, ctx: &ReqContext, content: &PublicationContentOutput) -> Decision {
+        for m in &self.moderators {
+            let decision = match timeout(self.moderation_timeout, m.moderate(ctx, content)).await {
+                Ok(Ok(decision)) => decision,
+                Ok(Err(err)) => Decision::Escalate(format!("{} failed, {}", m.name(), err)),
+                Err(_) => Decision::Escalate(format!("{} timed out", m.name())),
+            };
+            if decision != Decision::Approve {
+                return decision;
+            }
+        }
+        Decision::Approve
+    }
+
+    // hands the publication over to human reviewers with a new task.
+    // returns false if it was escalated already, e.g. by a run whose ack failed.
+    async fn escalate(
+        &self,
+        ctx: &ReqContext,
+        item: &NotificationOutput,
+        publ: &PublicationOutput,
+    ) -> anyhow::Result<bool> {
+        if self.reviewers.is_empty() {
+            anyhow::bail!("no reviewers configured for escalation");
+        }
+        let key = escalated_key(&item.tid);
+        let escalated = format!(
+            "{}/{}/{}/{}",
+            *publ.gid, *publ.cid, publ.language, publ.version
+        );
+        if self.storage.get_object::<String>(&key)?.as_ref() == Some(&escalated) {
+            return Ok(false);
+        }
+
+        let url = self.taskbase.join("/v1/task")?;
+        let _: bool = self
             .request(
+                &self.taskbase,
                 Method::POST,
                 url,
-                jid,
-                Some(&Pagination {
+                ctx,
+                Some(&CreateTaskInput {
                     uid: self.system_user.clone(),
-                    page_token: None,
-                    page_size: Some(1000),
-                    status: Some(0i8),
-                    fields: Some(vec!["payload".to_string()]),
+                    gid: item.gid.clone(),
+                    kind: REVIEW_KIND.to_string(),
+                    to: self.reviewers.clone(),
+                    payload: item.payload.clone(),
                 }),
             )
             .await?;
-        Ok(res)
+        if let Err(err) = self.storage.set_object(&key, &escalated) {
+            log::warn!(target: "job",
+                action = "escalate",
+                rid = &ctx.rid,
+                key = &key,
+                error = err.to_string();
+                "",
+            );
+        }
+        Ok(true)
     }
 
-    async fn ack_todo(&self, jid: &str, input: &AckTaskInput) -> anyhow::Result<()> {
+    // lists the todo items of all pages, only the items of the kind if it is given.
+    async fn list_todo(
+        &self,
+        ctx: &ReqContext,
+        kind: Option<&str>,
+    ) -> anyhow::Result<Vec<NotificationOutput>> {
+        let mut todo = self.list_notifications(ctx, 0, &["payload"]).await?;
+        todo.retain(|item| kind.map_or(true, |kind| item.kind == kind));
+        Ok(todo)
+    }
+
+    // lists the notifications of the RPA user in the status from every page, a page
+    // without a next_page_token or with an empty one is the last.
+    async fn list_notifications(
+        &self,
+        ctx: &ReqContext,
+        status: i8,
+        fields: &[&str],
+    ) -> anyhow::Result<Vec<NotificationOutput>> {
+        let mut items: Vec<NotificationOutput> = Vec::new();
+        let mut page_token: Option<PackObject<Vec<u8>>> = None;
+        loop {
+            let url = self.taskbase.join("/v1/notification/list")?;
+            let res: SuccessResponse<Vec<NotificationOutput>> = self
+                .request_response(
+                    &self.taskbase,
+                    Method::POST,
+                    url,
+                    ctx,
+                    Some(&Pagination {
+                        uid: self.system_user.clone(),
+                        page_token,
+                        page_size: Some(PAGE_SIZE),
+                        status: Some(status),
+                        fields: Some(fields.iter().map(|f| f.to_string()).collect()),
+                    }),
+                )
+                .await?;
+            items.extend(res.result);
+            page_token = match res.next_page_token {
+                Some(token) if !token.is_empty() => Some(token),
+                _ => return Ok(items),
+            };
+        }
+    }
+
+    async fn ack_todo(
+        &self,
+        ctx: &ReqContext,
+        input: &AckTaskInput,
+        audit: audit::Entry,
+    ) -> anyhow::Result<()> {
         let url = self.taskbase.join("/v1/task/ack")?;
-        let _: bool = self.request(Method::PATCH, url, jid, Some(input)).await?;
+        let _: bool = self
+            .request(&self.taskbase, Method::PATCH, url, ctx, Some(input))
+            .await?;
+        self.audit.append(audit);
+        self.delete_notification(ctx, input).await
+    }
 
+    async fn delete_notification(
+        &self,
+        ctx: &ReqContext,
+        input: &AckTaskInput,
+    ) -> anyhow::Result<()> {
         let url = self.taskbase.join("/v1/notification/delete")?;
-        let _: bool = self.request(Method::POST, url, jid, Some(input)).await?;
+        let _: bool = self
+            .request(&self.taskbase, Method::POST, url, ctx, Some(input))
+            .await?;
         Ok(())
     }
 
-    async fn remove_todo(&self, jid: &str, input: &DeleteTaskInput) -> anyhow::Result<()> {
+    // applies the changes of the reviewed items, returns the result of every item.
+    // The acks of an item are skipped if its status is not set.
+    async fn apply(
+        &self,
+        ctx: &ReqContext,
+        reviewed: &[(TodoItem, Changes)],
+    ) -> Vec<anyhow::Result<()>> {
+        let mut results: Vec<anyhow::Result<()>> = reviewed.iter().map(|_| Ok(())).collect();
+
+        let idx: Vec<usize> = (0..reviewed.len())
+            .filter(|&i| reviewed[i].1.status.is_some())
+            .collect();
+        let items: Vec<(&ReqContext, &(PublicationOutput, audit::Entry))> = idx
+            .iter()
+            .map(|&i| (&reviewed[i].0.ctx, reviewed[i].1.status.as_ref().unwrap()))
+            .collect();
+        for (i, res) in idx
+            .into_iter()
+            .zip(self.set_publication_statuses(ctx, &items).await)
+        {
+            results[i] = res;
+        }
+        for ((todo_item, changes), res) in reviewed.iter().zip(&results) {
+            if let (Some((publ, audit)), Ok(_)) = (&changes.status, res) {
+                if publ.status == 1 {
+                    self.emit_decision(&todo_item.ctx, "approve", audit);
+                    self.request_translation(
+                        &todo_item.ctx,
+                        PublicationInput {
+                            gid: publ.gid.clone(),
+                            cid: publ.cid.clone(),
+                            language: publ.language.clone(),
+                            version: publ.version,
+                        },
+                    );
+                } else {
+                    self.emit_decision(&todo_item.ctx, "reject", audit);
+                }
+            }
+        }
+
+        let idx: Vec<usize> = (0..reviewed.len())
+            .filter(|&i| results[i].is_ok())
+            .collect();
+        let items: Vec<(&ReqContext, &(AckTaskInput, audit::Entry))> = idx
+            .iter()
+            .map(|&i| (&reviewed[i].0.ctx, &reviewed[i].1.ack))
+            .collect();
+        for (i, res) in idx.into_iter().zip(self.ack_todos(ctx, &items).await) {
+            results[i] = res;
+        }
+        results
+    }
+
+    async fn set_publication_statuses(
+        &self,
+        ctx: &ReqContext,
+        items: &[(&ReqContext, &(PublicationOutput, audit::Entry))],
+    ) -> Vec<anyhow::Result<()>> {
+        let mut results = Vec::with_capacity(items.len());
+        for chunk in items.chunks(self.writing.batch_size) {
+            let inputs: Vec<&PublicationOutput> =
+                chunk.iter().map(|(_, (input, _))| input).collect();
+            match self
+                .batch(
+                    &self.writing,
+                    Method::PATCH,
+                    BATCH_UPDATE_STATUS,
+                    ctx,
+                    &inputs,
+                )
+                .await
+            {
+                Ok(Some(res)) => {
+                    for ((_, (_, audit)), res) in chunk.iter().zip(res) {
+                        if res.is_ok() {
+                            self.audit.append(audit.clone());
+                        }
+                        results.push(res);
+                    }
+                }
+                Ok(None) => {
+                    for (ctx, (input, audit)) in chunk {
+                        let res = self.set_publication_status(ctx, input, audit.clone()).await;
+                        results.push(res.map(|_| ()));
+                    }
+                }
+                Err(err) => chunk
+                    .iter()
+                    .for_each(|_| results.push(Err(batch_error(&err)))),
+            }
+        }
+        results
+    }
+
+    async fn ack_todos(
+        &self,
+        ctx: &ReqContext,
+        items: &[(&ReqContext, &(AckTaskInput, audit::Entry))],
+    ) -> Vec<anyhow::Result<()>> {
+        let mut results = Vec::with_capacity(items.len());
+        for chunk in items.chunks(self.taskbase.batch_size) {
+            let inputs: Vec<&AckTaskInput> = chunk.iter().map(|(_, (input, _))| input).collect();
+            let mut acked = match self
+                .batch(&self.taskbase, Method::PATCH, BATCH_ACK_TASK, ctx, &inputs)
+                .await
+            {
+                Ok(Some(res)) => {
+                    for ((_, (_, audit)), res) in chunk.iter().zip(&res) {
+                        if res.is_ok() {
+                            self.audit.append(audit.clone());
+                        }
+                    }
+                    res
+                }
+                Ok(None) => {
+                    for (ctx, (input, audit)) in chunk {
+                        results.push(self.ack_todo(ctx, input, audit.clone()).await);
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    chunk
+                        .iter()
+                        .for_each(|_| results.push(Err(batch_error(&err))));
+                    continue;
+                }
+            };
+
+            // the notifications of the acked tasks are deleted.
+            let idx: Vec<usize> = (0..chunk.len()).filter(|&i| acked[i].is_ok()).collect();
+            let inputs: Vec<&AckTaskInput> = idx.iter().map(|&i| inputs[i]).collect();
+            match self
+                .batch(
+                    &self.taskbase,
+                    Method::POST,
+                    BATCH_DELETE_NOTIFICATION,
+                    ctx,
+                    &inputs,
+                )
+                .await
+            {
+                Ok(Some(res)) => {
+                    for (&i, res) in idx.iter().zip(res) {
+                        acked[i] = res;
+                    }
+                }
+                Ok(None) => {
+                    for (&i, input) in idx.iter().zip(inputs) {
+                        acked[i] = self.delete_notification(chunk[i].0, input).await;
+                    }
+                }
+                Err(err) => {
+                    for &i in &idx {
+                        acked[i] = Err(batch_error(&err));
+                    }
+                }
+            }
+            results.extend(acked);
+        }
+        results
+    }
+
+    // calls the batch route with the items and returns the result of every item,
+    // or None if it is not called: a single item, batch calls are disabled, or the
+    // upstream answered 404 or 501 for the route, which is not called again then.
+    async fn batch<IN: Serialize>(
+        &self,
+        upstream: &Upstream,
+        method: Method,
+        path: &str,
+        ctx: &ReqContext,
+        items: &[&IN],
+    ) -> anyhow::Result<Option<Vec<anyhow::Result<()>>>> {
+        if items.len() < 2 || !upstream.batching(path) {
+            return Ok(None);
+        }
+        let url = upstream.join(path)?;
+        match self
+            .request::<_, Vec<BatchItemOutput>>(
+                upstream,
+                method,
+                url,
+                ctx,
+                Some(&BatchInput { items }),
+            )
+            .await
+        {
+            Ok(output) if output.len() != items.len() => Err(anyhow::anyhow!(
+                "batch call returned {} results for {} items",
+                output.len(),
+                items.len()
+            )),
+            Ok(output) => Ok(Some(
+                output
+                    .into_iter()
+                    .map(|item| match item.error {
+                        None => Ok(()),
+                        Some(err) => Err(anyhow::anyhow!("{}", err)),
+                    })
+                    .collect(),
+            )),
+            Err(err) => match err.downcast_ref::<HTTPError>() {
+                Some(e) if e.code == 404 || e.code == 501 => {
+                    log::warn!(target: "job",
+                        action = "batch",
+                        rid = &ctx.rid,
+                        upstream = &upstream.name,
+                        path = path,
+                        status = e.code;
+                        "not supported, fall back to per-item calls",
+                    );
+                    upstream.unbatched.lock().unwrap().insert(path.to_string());
+                    Ok(None)
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    async fn remove_todo(
+        &self,
+        ctx: &ReqContext,
+        input: &DeleteTaskInput,
+        audit: audit::Entry,
+    ) -> anyhow::Result<()> {
         let url = self.taskbase.join("/v1/task/delete")?;
-        let _: bool = self.request(Method::POST, url, jid, Some(input)).await?;
+        let _: bool = self
+            .request(&self.taskbase, Method::POST, url, ctx, Some(input))
+            .await?;
+        self.audit.append(audit);
         Ok(())
     }
 
     async fn get_publication(
         &self,
-        jid: &str,
+        ctx: &ReqContext,
         input: &PublicationInput,
     ) -> anyhow::Result<PublicationOutput> {
         let mut url = self.writing.join("/v1/publication")?;
@@ -310,18 +1472,767 @@ impl RPA {
             .append_pair("version", &input.version.to_string())
             .append_pair("fields", "status,updated_at,content_length");
         let res = self
-            .request::<(), PublicationOutput>(Method::GET, url, jid, None)
+            .request::<(), PublicationOutput>(&self.writing, Method::GET, url, ctx, None)
+            .await?;
+        Ok(res)
+    }
+
+    async fn get_publication_content(
+        &self,
+        ctx: &ReqContext,
+        input: &PublicationInput,
+    ) -> anyhow::Result<PublicationContentOutput> {
+        let mut url = self.writing.join("/v1/publication")?;
+        url.query_pairs_mut()
+            .append_pair("gid", &input.gid.to_string())
+            .append_pair("cid", &input.cid.to_string())
+            .append_pair("language", &input.language)
+            .append_pair("version", &input.version.to_string())
+            .append_pair("fields", "content");
+        let res = self
+            .request::<(), PublicationContentOutput>(&self.writing, Method::GET, url, ctx, None)
             .await?;
         Ok(res)
     }
 
     async fn set_publication_status(
         &self,
-        jid: &str,
+        ctx: &ReqContext,
         input: &PublicationOutput,
+        audit: audit::Entry,
     ) -> anyhow::Result<PublicationOutput> {
         let url = self.writing.join("/v1/publication/update_status")?;
-        let res: PublicationOutput = self.request(Method::PATCH, url, jid, Some(input)).await?;
+        let res: PublicationOutput = self
+            .request(&self.writing, Method::PATCH, url, ctx, Some(input))
+            .await?;
+        self.audit.append(audit);
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap},
+        response::IntoResponse,
+        routing, Json, Router,
+    };
+    use serde::de::IgnoredAny;
+    use std::collections::BTreeMap;
+
+    use super::testing::{serve, test_rpa};
+
+    async fn echo(headers: HeaderMap, input: PackObject<PublicationOutput>) -> impl IntoResponse {
+        let enc = Encoding::from_header_value(headers.get(header::CONTENT_ENCODING));
+        let mut output = input.unwrap();
+        output.language = enc.to_string();
+        let data = cbor_to_vec(&SuccessResponse::new(output)).unwrap();
+        (
+            [
+                (header::CONTENT_TYPE, "application/cbor"),
+                (header::CONTENT_ENCODING, "zstd"),
+            ],
+            Encoding::Zstd.encode_all(&data[..]).unwrap(),
+        )
+    }
+
+    async fn json_only(
+        headers: HeaderMap,
+        input: PackObject<PublicationOutput>,
+    ) -> impl IntoResponse {
+        let mut output = input.unwrap();
+        output.status = 1;
+        output.language = headers
+            .get("traceparent")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Json(SuccessResponse::new(output))
+    }
+
+    async fn tenant(headers: HeaderMap, input: PackObject<PublicationOutput>) -> impl IntoResponse {
+        let mut output = input.unwrap();
+        output.language = headers
+            .get("x-tenant")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Json(SuccessResponse::new(output))
+    }
+
+    async fn auth_user(
+        headers: HeaderMap,
+        input: PackObject<PublicationOutput>,
+    ) -> impl IntoResponse {
+        let mut output = input.unwrap();
+        output.language = headers
+            .get("x-auth-user")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Json(SuccessResponse::new(output))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn external_client() {
+        let app = Router::new().route("/auth", routing::post(auth_user));
+        let addr = serve(app);
+        let url = reqwest::Url::parse(&format!("http://{}/auth", addr)).unwrap();
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let input = PublicationOutput::default();
+        for (client, user) in [
+            (new_client().unwrap(), JARVIS),
+            (new_external_client().unwrap(), ""),
+        ] {
+            let output: PublicationOutput = send(
+                &client,
+                Encoding::Gzip,
+                PackObject::Json(()),
+                Method::POST,
+                url.clone(),
+                &ctx,
+                Some(&input),
+            )
+            .await
+            .unwrap();
+            assert_eq!(output.language, user);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_with_encoding() {
+        let app = Router::new().route("/echo", routing::post(echo));
+        let addr = serve(app);
+
+        let client = new_client().unwrap();
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let url = reqwest::Url::parse(&format!("http://{}/echo", addr)).unwrap();
+        let input = PublicationOutput {
+            content_length: 42,
+            ..Default::default()
+        };
+        let output: PublicationOutput = send(
+            &client,
+            Encoding::Zstd,
+            PackObject::Cbor(()),
+            Method::POST,
+            url.clone(),
+            &ctx,
+            Some(&input),
+        )
+        .await
+        .unwrap();
+        // small body is not compressed
+        assert_eq!(output.language, "identity");
+        assert_eq!(output.content_length, 42);
+
+        let input = PublicationOutput {
+            language: "x".repeat(COMPRESS_MIN_LENGTH),
+            ..Default::default()
+        };
+        for enc in [Encoding::Zstd, Encoding::Gzip] {
+            let output: PublicationOutput = send(
+                &client,
+                enc,
+                PackObject::Cbor(()),
+                Method::POST,
+                url.clone(),
+                &ctx,
+                Some(&input),
+            )
+            .await
+            .unwrap();
+            assert_eq!(output.language, enc.to_string());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_with_format() {
+        let app = Router::new()
+            .route("/echo", routing::post(echo))
+            .route("/json", routing::post(json_only));
+        let addr = serve(app);
+
+        let client = new_client().unwrap();
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let input = PublicationOutput {
+            gid: PackObject::Json(xid::new()),
+            content_length: 42,
+            ..Default::default()
+        };
+
+        // JSON response to a CBOR request
+        let url = reqwest::Url::parse(&format!("http://{}/json", addr)).unwrap();
+        let output: PublicationOutput = send(
+            &client,
+            Encoding::Gzip,
+            PackObject::Cbor(()),
+            Method::POST,
+            url,
+            &ctx,
+            Some(&input),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.gid, input.gid);
+        assert_eq!(output.status, 1);
+        assert_eq!(output.language, ctx.trace.traceparent());
+
+        // CBOR response to a JSON request
+        let url = reqwest::Url::parse(&format!("http://{}/echo", addr)).unwrap();
+        let output: PublicationOutput = send(
+            &client,
+            Encoding::Gzip,
+            PackObject::Json(()),
+            Method::POST,
+            url,
+            &ctx,
+            Some(&input),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*output.gid, *input.gid);
+        assert_eq!(output.content_length, 42);
+    }
+
+    type Calls = Arc<Mutex<BTreeMap<&'static str, usize>>>;
+
+    async fn update_status(
+        State(calls): State<Calls>,
+        to: PackObject<()>,
+        input: PackObject<PublicationOutput>,
+    ) -> PackObject<SuccessResponse<PublicationOutput>> {
+        *calls.lock().unwrap().entry("update_status").or_default() += 1;
+        to.with(SuccessResponse::new(input.unwrap()))
+    }
+
+    #[derive(Deserialize)]
+    struct BatchItems {
+        items: Vec<IgnoredAny>,
+    }
+
+    async fn batch_ack(
+        State(calls): State<Calls>,
+        to: PackObject<()>,
+        input: PackObject<BatchItems>,
+    ) -> PackObject<SuccessResponse<Vec<BatchItemOutput>>> {
+        let mut calls = calls.lock().unwrap();
+        *calls.entry("batch_ack").or_default() += 1;
+        *calls.entry("batch_ack_items").or_default() += input.items.len();
+        let res = input.items.iter().map(|_| BatchItemOutput::default());
+        to.with(SuccessResponse::new(res.collect()))
+    }
+
+    // fails the first item.
+    async fn batch_update_status(
+        State(calls): State<Calls>,
+        to: PackObject<()>,
+        input: PackObject<BatchItems>,
+    ) -> PackObject<SuccessResponse<Vec<BatchItemOutput>>> {
+        *calls
+            .lock()
+            .unwrap()
+            .entry("batch_update_status")
+            .or_default() += 1;
+        let res = (0..input.items.len()).map(|i| BatchItemOutput {
+            error: (i == 0).then(|| "conflict".to_string()),
+        });
+        to.with(SuccessResponse::new(res.collect()))
+    }
+
+    async fn delete_notification(
+        State(calls): State<Calls>,
+        to: PackObject<()>,
+        _input: PackObject<IgnoredAny>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        *calls.lock().unwrap().entry("delete").or_default() += 1;
+        to.with(SuccessResponse::new(true))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upstream_client() {
+        let app = Router::new().route("/tenant", routing::post(tenant));
+        let addr = serve(app);
+
+        let mut cfg = conf::Upstream {
+            format: conf::Format::Json,
+            timeout: 5,
+            pool_max_idle: 1,
+            ..Default::default()
+        };
+        cfg.headers
+            .insert("x-tenant".to_string(), "yiwen".to_string());
+        let upstream = Upstream::new("writing", &format!("http://{}", addr), Some(&cfg)).unwrap();
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let output: PublicationOutput = send(
+            &upstream.client,
+            upstream.encoding,
+            upstream.format.clone(),
+            Method::POST,
+            upstream.join("/tenant").unwrap(),
+            &ctx,
+            Some(&PublicationOutput::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.language, "yiwen");
+
+        // the default client does not carry the extra headers
+        let output: PublicationOutput = send(
+            &new_client().unwrap(),
+            upstream.encoding,
+            upstream.format.clone(),
+            Method::POST,
+            upstream.join("/tenant").unwrap(),
+            &ctx,
+            Some(&PublicationOutput::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.language, "");
+
+        let mut invalid = cfg.clone();
+        invalid
+            .headers
+            .insert("x tenant".to_string(), "yiwen".to_string());
+        assert!(build_client(&invalid).is_err());
+        for name in [
+            "Accept",
+            "accept-encoding",
+            "x-auth-user",
+            "X-Auth-User-Rating",
+        ] {
+            let mut invalid = cfg.clone();
+            invalid.headers.insert(name.to_string(), "0".to_string());
+            assert!(build_client(&invalid).is_err());
+        }
+        let mut invalid = cfg.clone();
+        invalid.proxy = "not a proxy".to_string();
+        assert!(build_client(&invalid).is_err());
+        invalid.proxy = "http://127.0.0.1:3128".to_string();
+        assert!(build_client(&invalid).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn apply_in_batches() {
+        let calls: Calls = Arc::new(Mutex::new(BTreeMap::new()));
+        // batch_update_status and batch_delete are not supported
+        let app = Router::new()
+            .route(
+                "/v1/publication/update_status",
+                routing::patch(update_status),
+            )
+            .route("/v1/task/batch_ack", routing::patch(batch_ack))
+            .route(
+                "/v1/notification/delete",
+                routing::post(delete_notification),
+            )
+            .with_state(calls.clone());
+        let (rpa, _) = test_rpa(app, |_, _| {}).await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let reviewed = |n: usize| reviewed_items(&ctx, n);
+
+        let results = rpa.apply(&ctx, &reviewed(3)).await;
+        assert!(results.iter().all(|res| res.is_ok()));
+        assert!(!rpa.writing.batching(BATCH_UPDATE_STATUS));
+        assert!(rpa.taskbase.batching(BATCH_ACK_TASK));
+        assert!(!rpa.taskbase.batching(BATCH_DELETE_NOTIFICATION));
+        {
+            let calls = calls.lock().unwrap();
+            assert_eq!(calls.get("update_status"), Some(&3));
+            assert_eq!(calls.get("batch_ack"), Some(&1));
+            assert_eq!(calls.get("delete"), Some(&3));
+        }
+
+        let results = rpa.apply(&ctx, &reviewed(2)).await;
+        assert!(results.iter().all(|res| res.is_ok()));
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.get("update_status"), Some(&5));
+        assert_eq!(calls.get("batch_ack"), Some(&2));
+        assert_eq!(calls.get("delete"), Some(&5));
+    }
+
+    fn reviewed_items(ctx: &ReqContext, n: usize) -> Vec<(TodoItem, Changes)> {
+        (0..n)
+            .map(|_| {
+                let tid = PackObject::Cbor(xid::new());
+                (
+                    TodoItem {
+                        ctx: ctx.child(),
+                        action: "publication_review",
+                        start: 0,
+                        sender: tid.clone(),
+                        tid: tid.clone(),
+                        failures_key: "".to_string(),
+                        publ: None,
+                    },
+                    Changes {
+                        status: Some((PublicationOutput::default(), audit::Entry::default())),
+                        ack: (
+                            AckTaskInput {
+                                uid: tid.clone(),
+                                tid: tid.clone(),
+                                sender: tid,
+                                status: 1,
+                                message: "".to_string(),
+                            },
+                            audit::Entry::default(),
+                        ),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch_item_results() {
+        let calls: Calls = Arc::new(Mutex::new(BTreeMap::new()));
+        let app = Router::new()
+            .route(
+                "/v1/publication/batch_update_status",
+                routing::patch(batch_update_status),
+            )
+            .route("/v1/task/batch_ack", routing::patch(batch_ack))
+            .route(
+                "/v1/notification/delete",
+                routing::post(delete_notification),
+            )
+            .with_state(calls.clone());
+        let (rpa, _) = test_rpa(app, |_, _| {}).await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let results = rpa.apply(&ctx, &reviewed_items(&ctx, 3)).await;
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "conflict".to_string()
+        );
+        assert!(results[1].is_ok());
+        assert!(results[2].is_ok());
+        // only the items whose status is set are acked
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.get("batch_update_status"), Some(&1));
+        assert_eq!(calls.get("batch_ack"), Some(&1));
+        assert_eq!(calls.get("batch_ack_items"), Some(&2));
+        assert_eq!(calls.get("delete"), Some(&2));
+    }
+
+    async fn list_pages(
+        to: PackObject<()>,
+        input: PackObject<Pagination>,
+    ) -> PackObject<SuccessResponse<Vec<NotificationOutput>>> {
+        let page = input.unwrap().page_token.map_or(0, |token| token[0]);
+        let item = || NotificationOutput {
+            tid: PackObject::Cbor(xid::new()),
+            ..Default::default()
+        };
+        let mut res = SuccessResponse::new(vec![item(), item()]);
+        // the last page has an empty token
+        res.next_page_token = Some(PackObject::Cbor(if page < 2 {
+            vec![page + 1]
+        } else {
+            vec![]
+        }));
+        to.with(res)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn list_todo_pages() {
+        let app = Router::new().route("/v1/notification/list", routing::post(list_pages));
+        let (rpa, _) = test_rpa(app, |_, _| {}).await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let todo = rpa.list_todo(&ctx, None).await.unwrap();
+        assert_eq!(todo.len(), 6);
+        let tids: BTreeSet<String> = todo.iter().map(|item| item.tid.to_string()).collect();
+        assert_eq!(tids.len(), 6);
+    }
+
+    async fn list_delayed(
+        State(tid): State<PackObject<xid::Id>>,
+        to: PackObject<()>,
+        input: PackObject<Pagination>,
+    ) -> PackObject<SuccessResponse<Vec<NotificationOutput>>> {
+        // a scheduled item on the first page, the delayed one on the second
+        let res = match input.unwrap().page_token {
+            None => {
+                let mut res = SuccessResponse::new(vec![NotificationOutput {
+                    tid: PackObject::Cbor(xid::new()),
+                    kind: PUBLISH_KIND.to_string(),
+                    ..Default::default()
+                }]);
+                res.next_page_token = Some(PackObject::Cbor(vec![1]));
+                res
+            }
+            Some(_) => SuccessResponse::new(vec![NotificationOutput {
+                tid,
+                kind: REVIEW_KIND.to_string(),
+                ..Default::default()
+            }]),
+        };
+        to.with(res)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keep_delayed_on_later_pages() {
+        let tid = PackObject::Cbor(xid::new());
+        let app = Router::new()
+            .route("/v1/notification/list", routing::post(list_delayed))
+            .with_state(tid.clone());
+        let (rpa, _) = test_rpa(app, |_, _| {}).await;
+
+        let due_at = unix_ms() + 3600 * 1000;
+        let delayed = |tid: &PackObject<xid::Id>| NotificationOutput {
+            tid: tid.clone(),
+            ..Default::default()
+        };
+        let gone = PackObject::Cbor(xid::new());
+        assert!(rpa.delayed.push(due_at, delayed(&tid)).unwrap());
+        assert!(rpa.delayed.push(due_at, delayed(&gone)).unwrap());
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        rpa.publication_review(&ctx, &rpa.scope(None))
+            .await
+            .unwrap();
+        assert!(rpa.delayed.contains(&tid.to_string()));
+        assert!(!rpa.delayed.contains(&gone.to_string()));
+    }
+
+    struct StubModerator {
+        name: &'static str,
+        delay: Duration,
+        fail: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Moderator for StubModerator {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn moderate(
+            &self,
+            _ctx: &ReqContext,
+            _publ: &PublicationContentOutput,
+        ) -> anyhow::Result<Decision> {
+            tokio::time::sleep(self.delay).await;
+            if self.fail {
+                anyhow::bail!("unavailable");
+            }
+            Ok(Decision::Approve)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn moderate_escalates() {
+        let (mut rpa, _) = test_rpa(Router::new(), |_, _| {}).await;
+        rpa.moderation_timeout = Duration::from_millis(50);
+        let moderator = |name, delay, fail| -> Box<dyn Moderator> {
+            Box::new(StubModerator { name, delay, fail })
+        };
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let content = PublicationContentOutput::default();
+        rpa.moderators = vec![
+            moderator("fast", Duration::ZERO, false),
+            moderator("slow", Duration::from_secs(5), false),
+        ];
+        let start = Instant::now();
+        assert_eq!(
+            rpa.moderate(&ctx, &content).await,
+            Decision::Escalate("slow timed out".to_string())
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        rpa.moderators = vec![moderator("broken", Duration::ZERO, true)];
+        assert_eq!(
+            rpa.moderate(&ctx, &content).await,
+            Decision::Escalate("broken failed, unavailable".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[should_panic(expected = "escalation needs reviewers")]
+    async fn escalation_needs_reviewers() {
+        test_rpa(Router::new(), |cfg, _| {
+            cfg.review.rules = vec![review::Rule::Language {
+                allow: vec!["eng".to_string()],
+                action: review::Action::Escalate,
+            }];
+        })
+        .await;
+    }
+
+    async fn create_task(
+        State(calls): State<Calls>,
+        to: PackObject<()>,
+        _input: PackObject<CreateTaskInput>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        *calls.lock().unwrap().entry("create_task").or_default() += 1;
+        to.with(SuccessResponse::new(true))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn escalate_once() {
+        let calls: Calls = Arc::new(Mutex::new(BTreeMap::new()));
+        let app = Router::new()
+            .route("/v1/task", routing::post(create_task))
+            .with_state(calls.clone());
+        let (mut rpa, _) = test_rpa(app, |_, _| {}).await;
+        rpa.reviewers = vec![PackObject::Cbor(xid::new())];
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let item = NotificationOutput {
+            tid: PackObject::Cbor(xid::new()),
+            ..Default::default()
+        };
+        let mut publ = PublicationOutput::default();
+        assert!(rpa.escalate(&ctx, &item, &publ).await.unwrap());
+        assert!(!rpa.escalate(&ctx, &item, &publ).await.unwrap());
+        assert_eq!(calls.lock().unwrap()["create_task"], 1);
+
+        // a new version of the publication is escalated again
+        publ.version = 2;
+        assert!(rpa.escalate(&ctx, &item, &publ).await.unwrap());
+        assert_eq!(calls.lock().unwrap()["create_task"], 2);
+
+        rpa.clear_failures(&ctx, &escalated_key(&item.tid));
+        assert!(rpa.escalate(&ctx, &item, &publ).await.unwrap());
+        assert_eq!(calls.lock().unwrap()["create_task"], 3);
+    }
+
+    async fn too_many_requests() -> impl IntoResponse {
+        (
+            axum::http::StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "0")],
+            "slow down",
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rate_limited_is_unavailable() {
+        let app = Router::new().route("/v1/task/ack", routing::patch(too_many_requests));
+        let (rpa, _) = test_rpa(app, |cfg, _| {
+            cfg.upstreams.insert(
+                "taskbase".to_string(),
+                conf::Upstream {
+                    max_retries: 1,
+                    ..Default::default()
+                },
+            );
+        })
+        .await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let tid = PackObject::Cbor(xid::new());
+        let input = AckTaskInput {
+            uid: tid.clone(),
+            tid: tid.clone(),
+            sender: tid,
+            status: 1,
+            message: "".to_string(),
+        };
+        let err = rpa
+            .ack_todo(&ctx, &input, audit::Entry::default())
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<Unavailable>().unwrap();
+        assert!(err.reason.starts_with("rate limited after 1 retries"));
+    }
+
+    async fn internal_error() -> impl IntoResponse {
+        (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "poison item")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unavailable_when_circuit_opens() {
+        let app = Router::new().route("/v1/task/ack", routing::patch(internal_error));
+        let (rpa, _) = test_rpa(app, |cfg, _| {
+            cfg.breaker = conf::Breaker {
+                failure_threshold: 2,
+                open_timeout: 3600,
+                half_open_requests: 1,
+            };
+        })
+        .await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let tid = PackObject::Cbor(xid::new());
+        let input = AckTaskInput {
+            uid: tid.clone(),
+            tid: tid.clone(),
+            sender: tid,
+            status: 1,
+            message: "".to_string(),
+        };
+        // counted against the item
+        let err = rpa
+            .ack_todo(&ctx, &input, audit::Entry::default())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<Unavailable>().is_none());
+        // the failure that opens the circuit, and the calls while it is open
+        for _ in 0..2 {
+            let err = rpa
+                .ack_todo(&ctx, &input, audit::Entry::default())
+                .await
+                .unwrap_err();
+            assert!(err.downcast_ref::<Unavailable>().is_some());
+        }
+    }
+
+    async fn delete_task(
+        to: PackObject<()>,
+        _input: PackObject<IgnoredAny>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        to.with(SuccessResponse::new(true))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn audit_removed_todo() {
+        let path = std::env::temp_dir().join(format!("rpa-audit-{}.jsonl", xid::new()));
+        let app = Router::new().route("/v1/task/delete", routing::post(delete_task));
+        let (rpa, _) = test_rpa(app, |cfg, _| {
+            cfg.audit.path = path.to_str().unwrap().to_string();
+            cfg.review.max_failures = 1;
+        })
+        .await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let tid = PackObject::Cbor(xid::new());
+        let publ = PublicationInput {
+            gid: PackObject::Cbor(xid::new()),
+            cid: PackObject::Cbor(xid::new()),
+            language: "eng".to_string(),
+            version: 1,
+        };
+        let todo_item = TodoItem {
+            ctx: ctx.child(),
+            action: "publication_review",
+            start: 0,
+            sender: tid.clone(),
+            tid,
+            failures_key: format!("{}test", REVIEW_FAILURES),
+            publ: Some(publ),
+        };
+        let start = Instant::now();
+        let res = Err(anyhow::anyhow!("invalid payload"));
+        assert!(rpa.finish_todo(&start, &todo_item, res).await);
+
+        let publ = todo_item.publ.as_ref().unwrap();
+        let entries = rpa
+            .audit
+            .query(&audit::Query {
+                gid: Some(publ.gid.to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "remove_todo");
+        assert_eq!(entries[0].cid, publ.cid.to_string());
+        assert_eq!(entries[0].reason, "invalid payload");
+        let _ = std::fs::remove_file(path);
+    }
+}
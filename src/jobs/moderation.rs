@@ -0,0 +1,419 @@
+use async_trait::async_trait;
+use ciborium::value::Value;
+use regex::Regex;
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    review::{Action, Decision},
+    PublicationContentOutput,
+};
+use crate::conf;
+use axum_web::{context::ReqContext, encoding::Encoding, object::PackObject};
+
+// Moderator decides whether the content of a publication can be published.
+// Decision::Escalate means the publication needs human review.
+#[async_trait]
+pub trait Moderator: Send + Sync {
+    fn name(&self) -> &str;
+
+    // reloads the moderator's configuration if it changed.
+    fn reload(&self) -> Vec<anyhow::Error> {
+        vec![]
+    }
+
+    async fn moderate(
+        &self,
+        ctx: &ReqContext,
+        publ: &PublicationContentOutput,
+    ) -> anyhow::Result<Decision>;
+}
+
+pub fn new_moderators(cfg: &conf::Moderation) -> anyhow::Result<Vec<Box<dyn Moderator>>> {
+    let mut moderators: Vec<Box<dyn Moderator>> = Vec::new();
+    if !cfg.lists.is_empty() {
+        moderators.push(Box::new(Blocklists::new(cfg)?));
+    }
+    for service in &cfg.services {
+        moderators.push(Box::new(HttpModerator::new(service)?));
+    }
+    Ok(moderators)
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListKind {
+    Keyword,
+    Regex,
+}
+
+enum Matcher {
+    Keywords(Vec<String>), // lowercased
+    Patterns(Vec<Regex>),
+}
+
+struct List {
+    cfg: conf::ModerationList,
+    version: (SystemTime, u64), // modification time and length of the file
+    matcher: Matcher,
+}
+
+// Scans publication content against blocklists and regex patterns loaded from files.
+// A list file holds one keyword or pattern per line, empty lines and lines starting
+// with "#" are ignored. Files are reloaded when their modification time or length changes.
+pub struct Blocklists {
+    lists: RwLock<Vec<List>>,
+}
+
+impl Blocklists {
+    pub fn new(cfg: &conf::Moderation) -> anyhow::Result<Self> {
+        let mut lists = Vec::with_capacity(cfg.lists.len());
+        for list in &cfg.lists {
+            lists.push(load_list(list)?);
+        }
+
+        Ok(Self {
+            lists: RwLock::new(lists),
+        })
+    }
+
+    pub fn scan(&self, texts: &[String]) -> Decision {
+        let lists = self.lists.read().unwrap();
+        let lowercased: Vec<String> = texts.iter().map(|t| t.to_lowercase()).collect();
+        for list in lists.iter() {
+            let matched = match &list.matcher {
+                Matcher::Keywords(words) => words
+                    .iter()
+                    .find(|w| lowercased.iter().any(|t| t.contains(w.as_str())))
+                    .map(|w| format!("keyword {:?}", w)),
+                Matcher::Patterns(patterns) => patterns
+                    .iter()
+                    .find(|p| texts.iter().any(|t| p.is_match(t)))
+                    .map(|p| format!("pattern {:?}", p.as_str())),
+            };
+
+            if let Some(matched) = matched {
+                let reason = format!("matched {} in {:?}", matched, list.cfg.name);
+                return match list.cfg.action {
+                    Action::Reject => Decision::Reject(reason),
+                    Action::Escalate => Decision::Escalate(reason),
+                };
+            }
+        }
+
+        Decision::Approve
+    }
+}
+
+#[async_trait]
+impl Moderator for Blocklists {
+    fn name(&self) -> &str {
+        "blocklists"
+    }
+
+    // reloads the changed list files, a list that fails to load keeps its previous version.
+    fn reload(&self) -> Vec<anyhow::Error> {
+        let mut errs = Vec::new();
+        let mut lists = self.lists.write().unwrap();
+        for list in lists.iter_mut() {
+            match file_version(&list.cfg.path) {
+                Ok(version) if version == list.version => {}
+                Ok(_) => match load_list(&list.cfg) {
+                    Ok(l) => *list = l,
+                    Err(err) => errs.push(err),
+                },
+                Err(err) => errs.push(err),
+            }
+        }
+        errs
+    }
+
+    async fn moderate(
+        &self,
+        _ctx: &ReqContext,
+        publ: &PublicationContentOutput,
+    ) -> anyhow::Result<Decision> {
+        Ok(self.scan(&extract_texts(&publ.content)))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Approve,
+    Reject,
+    Review,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModerationInput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: String,
+    pub version: i16,
+    pub content: PackObject<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModerationOutput {
+    pub verdict: Verdict,
+    pub reason: String,
+}
+
+// Asks an external moderation service, the content is POSTed to the endpoint
+// with the same CBOR and gzip conventions as the upstream requests.
+pub struct HttpModerator {
+    name: String,
+    client: Client,
+    endpoint: reqwest::Url,
+}
+
+impl HttpModerator {
+    pub fn new(cfg: &conf::ModerationService) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: cfg.name.clone(),
+            client: super::new_external_client()?,
+            endpoint: reqwest::Url::parse(&cfg.endpoint)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Moderator for HttpModerator {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn moderate(
+        &self,
+        ctx: &ReqContext,
+        publ: &PublicationContentOutput,
+    ) -> anyhow::Result<Decision> {
+        let output: ModerationOutput = super::send(
+            &self.client,
+            Encoding::Gzip,
+            PackObject::Cbor(()),
+            Method::POST,
+            self.endpoint.clone(),
+            ctx,
+            Some(&ModerationInput {
+                gid: publ.gid.clone(),
+                cid: publ.cid.clone(),
+                language: publ.language.clone(),
+                version: publ.version,
+                content: publ.content.clone(),
+            }),
+        )
+        .await?;
+
+        Ok(match output.verdict {
+            Verdict::Approve => Decision::Approve,
+            Verdict::Reject => Decision::Reject(format!("{}: {}", self.name, output.reason)),
+            Verdict::Review => Decision::Escalate(format!("{}: {}", self.name, output.reason)),
+        })
+    }
+}
+
+// extracts all text strings from the CBOR encoded publication content,
+// falls back to the raw bytes if the content is not valid CBOR.
+pub fn extract_texts(content: &[u8]) -> Vec<String> {
+    let mut texts = Vec::new();
+    match ciborium::from_reader::<Value, _>(content) {
+        Ok(value) => collect_texts(&value, &mut texts),
+        Err(_) => texts.push(String::from_utf8_lossy(content).to_string()),
+    }
+    texts
+}
+
+fn collect_texts(value: &Value, texts: &mut Vec<String>) {
+    match value {
+        Value::Text(s) => texts.push(s.clone()),
+        Value::Array(arr) => {
+            for v in arr {
+                collect_texts(v, texts);
+            }
+        }
+        Value::Map(map) => {
+            for (_, v) in map {
+                collect_texts(v, texts);
+            }
+        }
+        Value::Tag(_, v) => collect_texts(v, texts),
+        _ => {}
+    }
+}
+
+fn file_version(path: &str) -> anyhow::Result<(SystemTime, u64)> {
+    let meta =
+        fs::metadata(path).map_err(|err| anyhow::anyhow!("failed to read {:?}, {}", path, err))?;
+    Ok((meta.modified().unwrap_or(UNIX_EPOCH), meta.len()))
+}
+
+fn load_list(cfg: &conf::ModerationList) -> anyhow::Result<List> {
+    let version = file_version(&cfg.path)?;
+    let data = fs::read_to_string(&cfg.path)
+        .map_err(|err| anyhow::anyhow!("failed to read {:?}, {}", cfg.path, err))?;
+    let lines = data
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'));
+
+    let matcher = match cfg.kind {
+        ListKind::Keyword => Matcher::Keywords(lines.map(|l| l.to_lowercase()).collect()),
+        ListKind::Regex => {
+            let mut patterns = Vec::new();
+            for l in lines {
+                patterns.push(Regex::new(l).map_err(|err| {
+                    anyhow::anyhow!("invalid pattern {:?} in {:?}, {}", l, cfg.path, err)
+                })?);
+            }
+            Matcher::Patterns(patterns)
+        }
+    };
+
+    Ok(List {
+        cfg: cfg.clone(),
+        version,
+        matcher,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing, Router};
+    use axum_web::erring::SuccessResponse;
+
+    use super::super::testing::serve;
+
+    #[test]
+    fn scan_content() {
+        let dir = std::env::temp_dir().join(format!("yiwen-rpa-moderation-{}", xid::new()));
+        fs::create_dir_all(&dir).unwrap();
+        let keywords = dir.join("keywords.txt");
+        let patterns = dir.join("patterns.txt");
+        fs::write(&keywords, "# spam words\nBuy Now\n\ncasino\n").unwrap();
+        fs::write(&patterns, r"\b\d{3}-\d{4}-\d{4}\b").unwrap();
+
+        let moderation = Blocklists::new(&conf::Moderation {
+            lists: vec![
+                conf::ModerationList {
+                    name: "spam".to_string(),
+                    path: keywords.to_str().unwrap().to_string(),
+                    kind: ListKind::Keyword,
+                    action: Action::Reject,
+                },
+                conf::ModerationList {
+                    name: "phone".to_string(),
+                    path: patterns.to_str().unwrap().to_string(),
+                    kind: ListKind::Regex,
+                    action: Action::Escalate,
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut content: Vec<u8> = Vec::new();
+        ciborium::into_writer(
+            &Value::Array(vec![
+                Value::Text("Hello".to_string()),
+                Value::Map(vec![(
+                    Value::Text("texts".to_string()),
+                    Value::Array(vec![Value::Text("call 138-0000-0000".to_string())]),
+                )]),
+            ]),
+            &mut content,
+        )
+        .unwrap();
+        let texts = extract_texts(&content);
+        assert_eq!(texts, vec!["Hello", "call 138-0000-0000"]);
+        assert_eq!(
+            moderation.scan(&texts),
+            Decision::Escalate(
+                r#"matched pattern "\\b\\d{3}-\\d{4}-\\d{4}\\b" in "phone""#.to_string()
+            )
+        );
+
+        let texts = vec!["BUY NOW!".to_string()];
+        assert_eq!(
+            moderation.scan(&texts),
+            Decision::Reject(r#"matched keyword "buy now" in "spam""#.to_string())
+        );
+
+        fs::write(&keywords, "lottery\n").unwrap();
+        assert!(moderation.reload().is_empty());
+        assert_eq!(moderation.scan(&texts), Decision::Approve);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn moderate_stub(
+        input: PackObject<ModerationInput>,
+    ) -> PackObject<SuccessResponse<ModerationOutput>> {
+        let (to, input) = input.unpack();
+        let texts = extract_texts(&input.content);
+        let output = if texts.iter().any(|t| t.contains("casino")) {
+            ModerationOutput {
+                verdict: Verdict::Reject,
+                reason: "gambling".to_string(),
+            }
+        } else if input.language != "eng" {
+            ModerationOutput {
+                verdict: Verdict::Review,
+                reason: format!("unsupported language {}", input.language),
+            }
+        } else {
+            ModerationOutput {
+                verdict: Verdict::Approve,
+                reason: "".to_string(),
+            }
+        };
+        to.with(SuccessResponse::new(output))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_moderator() {
+        let app = Router::new().route("/v1/moderate", routing::post(moderate_stub));
+        let addr = serve(app);
+
+        let moderator = HttpModerator::new(&conf::ModerationService {
+            name: "stub".to_string(),
+            endpoint: format!("http://{}/v1/moderate", addr),
+        })
+        .unwrap();
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let mut content: Vec<u8> = Vec::new();
+        // large enough to be gzipped
+        ciborium::into_writer(&Value::Text("Hello world. ".repeat(100)), &mut content).unwrap();
+        let mut publ = PublicationContentOutput {
+            language: "eng".to_string(),
+            content: PackObject::Cbor(content),
+            ..Default::default()
+        };
+        assert_eq!(
+            moderator.moderate(&ctx, &publ).await.unwrap(),
+            Decision::Approve
+        );
+
+        publ.language = "fra".to_string();
+        assert_eq!(
+            moderator.moderate(&ctx, &publ).await.unwrap(),
+            Decision::Escalate("stub: unsupported language fra".to_string())
+        );
+
+        let mut content: Vec<u8> = Vec::new();
+        ciborium::into_writer(&Value::Text("casino".to_string()), &mut content).unwrap();
+        publ.content = PackObject::Cbor(content);
+        assert_eq!(
+            moderator.moderate(&ctx, &publ).await.unwrap(),
+            Decision::Reject("stub: gambling".to_string())
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use axum_web::{
+    context::{unix_ms, ReqContext},
+    object::{cbor_from_slice, PackObject},
+};
+
+use super::{
+    cancel::Scope, AckTaskInput, NotificationOutput, PublicationInput, TodoItem, PUBLISH_KIND, RPA,
+};
+use crate::{
+    audit,
+    telemetry::{Span, SpanKind},
+};
+
+// storage key prefix of the failure counters
+const PUBLISH_FAILURES: &str = "publish:failures:";
+// a run waits for the items scheduled before the next run.
+const PUBLISH_WINDOW: i64 = 60 * 1000;
+// the publication status after it is published
+pub const PUBLISHED: i8 = 2;
+
+// the payload of a scheduled-publish notification.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ScheduledPublishInput {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: String,
+    pub version: i16,
+    pub publish_at: i64, // unix milliseconds
+}
+
+impl RPA {
+    // publishes the approved publications that are scheduled before the next run,
+    // each one at its scheduled time.
+    pub(super) async fn scheduled_publish(
+        &self,
+        ctx: &ReqContext,
+        scope: &Scope,
+    ) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let todo = self.list_todo(ctx, Some(PUBLISH_KIND)).await?;
+        let todo_keys: BTreeSet<String> = todo
+            .iter()
+            .map(|item| format!("{}{}", PUBLISH_FAILURES, *item.tid))
+            .collect();
+
+        let until = unix_ms() as i64 + PUBLISH_WINDOW;
+        let mut due: Vec<(ScheduledPublishInput, NotificationOutput)> = Vec::new();
+        for item in todo {
+            if !self.shard.owns(&item.tid) {
+                continue;
+            }
+            match cbor_from_slice::<ScheduledPublishInput>(&item.payload) {
+                Ok(input) if input.publish_at < until => due.push((input, item)),
+                Ok(_) => {}
+                Err(err) => {
+                    let todo_item = self.publish_todo_item(ctx, &start, &item);
+                    self.finish_todo(&start, &todo_item, Err(err.into())).await;
+                }
+            }
+        }
+        due.sort_by_key(|(input, _)| input.publish_at);
+        log::info!(target: "job",
+            action = "list_scheduled",
+            rid = &ctx.rid,
+            trace = &ctx.trace.trace_id,
+            span = &ctx.trace.span_id,
+            shard = self.shard.index,
+            total = todo_keys.len(),
+            due = due.len();
+            "start",
+        );
+
+        for (input, item) in due {
+            scope.check()?;
+            let wait = input.publish_at - unix_ms() as i64;
+            if wait > 0 {
+                scope.sleep(Duration::from_millis(wait as u64)).await?;
+            }
+
+            let todo_item = self.publish_todo_item(ctx, &start, &item);
+            let ctx = &todo_item.ctx;
+            let mut span = Span::new("scheduled_publish_item", SpanKind::Internal, &ctx.trace);
+            span.attr("rid", &ctx.rid)
+                .attr("action", "scheduled_publish")
+                .attr("tid", todo_item.tid.to_string());
+            let res = scope
+                .run(self.scheduled_publish_item(ctx, &input, item))
+                .await;
+            if let Err(err) = &res {
+                span.error(err);
+            }
+            self.tracer.finish(span);
+            if !self.finish_todo(&start, &todo_item, res).await {
+                // keep the rest for the next run.
+                return Ok(());
+            }
+        }
+
+        // counters of tasks that are gone
+        for key in self.storage.keys(PUBLISH_FAILURES).unwrap_or_default() {
+            if !todo_keys.contains(&key) {
+                self.clear_failures(ctx, &key);
+            }
+        }
+        Ok(())
+    }
+
+    fn publish_todo_item(
+        &self,
+        ctx: &ReqContext,
+        start: &Instant,
+        item: &NotificationOutput,
+    ) -> TodoItem {
+        TodoItem {
+            ctx: ctx.child(),
+            action: "scheduled_publish",
+            start: start.elapsed().as_millis() as u64,
+            sender: item.sender.clone(),
+            tid: item.tid.clone(),
+            failures_key: format!("{}{}", PUBLISH_FAILURES, *item.tid),
+            publ: cbor_from_slice::<ScheduledPublishInput>(&item.payload)
+                .ok()
+                .map(|input| PublicationInput {
+                    gid: input.gid,
+                    cid: input.cid,
+                    language: input.language,
+                    version: input.version,
+                }),
+        }
+    }
+
+    // publishes an approved publication, one in review is left for the next run.
+    async fn scheduled_publish_item(
+        &self,
+        ctx: &ReqContext,
+        input: &ScheduledPublishInput,
+        item: NotificationOutput,
+    ) -> anyhow::Result<()> {
+        let mut publ = self
+            .get_publication(
+                ctx,
+                &PublicationInput {
+                    gid: input.gid.clone(),
+                    cid: input.cid.clone(),
+                    language: input.language.clone(),
+                    version: input.version,
+                },
+            )
+            .await?;
+
+        let audit = audit::Entry::new(ctx, &item.tid).publication(
+            &publ.gid,
+            &publ.cid,
+            &publ.language,
+            publ.version,
+        );
+        let (status, message) = match publ.status {
+            0 => {
+                log::info!(target: "job",
+                    action = "scheduled_publish",
+                    rid = &ctx.rid,
+                    span = &ctx.trace.span_id,
+                    tid = item.tid.to_string();
+                    "waiting for review",
+                );
+                return Ok(());
+            }
+            1 => {
+                publ.status = PUBLISHED;
+                let audit = audit.clone().reason("scheduled").action(
+                    "set_publication_status",
+                    Some(1),
+                    Some(PUBLISHED),
+                );
+                self.set_publication_status(ctx, &publ, audit).await?;
+                (1, "published".to_string())
+            }
+            PUBLISHED => (1, "skipped: already published".to_string()),
+            status => (-1, format!("skipped: status {}", status)),
+        };
+
+        let audit = audit
+            .reason(&message)
+            .action("ack_todo", Some(item.ack_status), Some(status));
+        self.ack_todo(
+            ctx,
+            &AckTaskInput {
+                uid: self.system_user.clone(),
+                tid: item.tid,
+                sender: item.sender,
+                status,
+                message,
+            },
+            audit,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing, Router};
+    use axum_web::{erring::SuccessResponse, object::cbor_to_vec};
+    use serde::de::IgnoredAny;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::{testing::test_rpa, Pagination, PublicationOutput, REVIEW_KIND};
+
+    type Published = Arc<Mutex<Vec<i8>>>;
+
+    async fn list(
+        to: PackObject<()>,
+        input: PackObject<Pagination>,
+    ) -> PackObject<SuccessResponse<Vec<NotificationOutput>>> {
+        let item = |publish_at: i64| NotificationOutput {
+            tid: PackObject::Cbor(xid::new()),
+            kind: PUBLISH_KIND.to_string(),
+            payload: PackObject::Cbor(
+                cbor_to_vec(&ScheduledPublishInput {
+                    language: "eng".to_string(),
+                    version: 1,
+                    publish_at,
+                    ..Default::default()
+                })
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let now = unix_ms() as i64;
+        // a review item and one left for a later run on the first page, the due one
+        // on the second
+        let res = match input.unwrap().page_token {
+            None => {
+                let review = NotificationOutput {
+                    tid: PackObject::Cbor(xid::new()),
+                    kind: REVIEW_KIND.to_string(),
+                    ..Default::default()
+                };
+                let mut res = SuccessResponse::new(vec![review, item(now + PUBLISH_WINDOW * 2)]);
+                res.next_page_token = Some(PackObject::Cbor(vec![1]));
+                res
+            }
+            Some(_) => SuccessResponse::new(vec![item(now + 50)]),
+        };
+        to.with(res)
+    }
+
+    async fn get_publication() -> PackObject<SuccessResponse<PublicationOutput>> {
+        PackObject::Cbor(SuccessResponse::new(PublicationOutput {
+            status: 1,
+            ..Default::default()
+        }))
+    }
+
+    async fn update_status(
+        State(published): State<Published>,
+        to: PackObject<()>,
+        input: PackObject<PublicationOutput>,
+    ) -> PackObject<SuccessResponse<PublicationOutput>> {
+        let input = input.unwrap();
+        published.lock().unwrap().push(input.status);
+        to.with(SuccessResponse::new(input))
+    }
+
+    async fn ok(
+        to: PackObject<()>,
+        _input: PackObject<IgnoredAny>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        to.with(SuccessResponse::new(true))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_in_time() {
+        let published: Published = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/v1/notification/list", routing::post(list))
+            .route("/v1/publication", routing::get(get_publication))
+            .route(
+                "/v1/publication/update_status",
+                routing::patch(update_status),
+            )
+            .route("/v1/task/ack", routing::patch(ok))
+            .route("/v1/notification/delete", routing::post(ok))
+            .with_state(published.clone());
+        let (rpa, _) = test_rpa(app, |_, _| {}).await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let start = unix_ms();
+        rpa.scheduled_publish(&ctx, &rpa.scope(None)).await.unwrap();
+        assert!(unix_ms() >= start + 50);
+        assert_eq!(*published.lock().unwrap(), vec![PUBLISHED]);
+    }
+}
@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::{
+    error::Error,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+// the longest Retry-After we honour.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// RateLimited is returned when an upstream responds with 429 Too Many Requests.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+    pub message: String,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "429: {}, retry after {}ms",
+            self.message,
+            self.retry_after.as_millis()
+        )
+    }
+}
+
+impl Error for RateLimited {}
+
+impl RateLimited {
+    pub fn new(headers: &HeaderMap, message: String) -> Self {
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after)
+            .unwrap_or(Duration::from_secs(1));
+        Self {
+            retry_after: retry_after.min(MAX_RETRY_AFTER),
+            message,
+        }
+    }
+}
+
+// Retry-After is either a number of seconds or a HTTP date.
+fn parse_retry_after(val: &str) -> Option<Duration> {
+    if let Ok(secs) = val.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(val.trim()).ok()?;
+    let ms = at.with_timezone(&Utc).timestamp_millis() - Utc::now().timestamp_millis();
+    Some(Duration::from_millis(ms.max(0) as u64))
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    paused_until: Option<Instant>,
+}
+
+// Limiter is a token bucket that allows `rate` requests per second with bursts
+// of up to `burst` requests. A zero rate means no limit.
+pub struct Limiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Limiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = (burst.max(1)) as f64;
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    // waits until a request is allowed.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = self.try_acquire();
+            if wait.is_zero() {
+                return;
+            }
+            sleep(wait).await;
+        }
+    }
+
+    // pauses all requests for the duration, e.g. as told by Retry-After.
+    pub fn pause(&self, d: Duration) {
+        let mut bucket = self.bucket.lock().unwrap();
+        let until = Instant::now() + d;
+        if bucket.paused_until.map_or(true, |t| t < until) {
+            bucket.paused_until = Some(until);
+        }
+    }
+
+    // takes a token and returns zero, or returns how long to wait for the next token.
+    fn try_acquire(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        if let Some(until) = bucket.paused_until {
+            if until > now {
+                return until - now;
+            }
+            bucket.paused_until = None;
+        }
+
+        if self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[tokio::test]
+    async fn limiter() {
+        let limiter = Limiter::new(100.0, 2);
+        let start = Instant::now();
+        for _ in 0..2 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(10));
+
+        for _ in 0..2 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(15));
+
+        let start = Instant::now();
+        limiter.pause(Duration::from_millis(50));
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let unlimited = Limiter::new(0.0, 0);
+        let start = Instant::now();
+        for _ in 0..1000 {
+            unlimited.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(10));
+    }
+
+    #[test]
+    fn retry_after() {
+        let mut headers = HeaderMap::new();
+        let err = RateLimited::new(&headers, "".to_string());
+        assert_eq!(err.retry_after, Duration::from_secs(1));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        let err = RateLimited::new(&headers, "".to_string());
+        assert_eq!(err.retry_after, Duration::from_secs(3));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3600"));
+        let err = RateLimited::new(&headers, "".to_string());
+        assert_eq!(err.retry_after, MAX_RETRY_AFTER);
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        let err = RateLimited::new(&headers, "".to_string());
+        assert_eq!(err.retry_after, Duration::ZERO);
+    }
+}
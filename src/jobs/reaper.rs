@@ -0,0 +1,252 @@
+use reqwest::Method;
+use std::{collections::BTreeMap, str::FromStr};
+
+use axum_web::{
+    context::{unix_ms, ReqContext},
+    object::{cbor_from_slice, PackObject},
+};
+
+use super::{
+    breaker::Unavailable, cancel::Scope, publish::ScheduledPublishInput, AckTaskInput,
+    CreateTaskInput, NotificationOutput, PUBLISH_KIND, RPA,
+};
+use crate::{audit, conf};
+
+const HOUR: i64 = 3600 * 1000;
+
+// Reaper acks the notifications of the RPA user that are not handled in max_age
+// as expired, or escalates them to a human user.
+pub struct Reaper {
+    max_age: i64, // milliseconds
+    statuses: Vec<i8>,
+    escalate_to: Option<PackObject<xid::Id>>,
+}
+
+impl Reaper {
+    pub fn new(cfg: &conf::Reaper, format: &PackObject<()>) -> anyhow::Result<Self> {
+        let escalate_to = if cfg.escalate_to.is_empty() {
+            None
+        } else {
+            let uid = xid::Id::from_str(&cfg.escalate_to)
+                .map_err(|err| anyhow::anyhow!("invalid user {:?}, {}", cfg.escalate_to, err))?;
+            Some(format.with(uid))
+        };
+        Ok(Self {
+            max_age: cfg.max_age as i64 * 1000,
+            statuses: cfg.statuses.clone(),
+            escalate_to,
+        })
+    }
+}
+
+// returns the time the item is waiting since, a scheduled one waits since its publish time.
+fn waiting_since(item: &NotificationOutput) -> i64 {
+    if item.kind == PUBLISH_KIND {
+        if let Ok(input) = cbor_from_slice::<ScheduledPublishInput>(&item.payload) {
+            return item.created_at.max(input.publish_at);
+        }
+    }
+    item.created_at
+}
+
+impl RPA {
+    pub(super) async fn reap_stale(
+        &self,
+        ctx: &ReqContext,
+        scope: &Scope,
+    ) -> anyhow::Result<BTreeMap<String, u64>> {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        if self.reaper.max_age <= 0 {
+            return Ok(counts);
+        }
+
+        let before = unix_ms() as i64 - self.reaper.max_age;
+        let mut stale: Vec<NotificationOutput> = Vec::new();
+        for status in &self.reaper.statuses {
+            let items = self
+                .list_notifications(ctx, *status, &["payload", "created_at"])
+                .await?;
+            *counts.entry("total".to_string()).or_default() += items.len() as u64;
+            // an item without created_at is never stale.
+            stale.extend(items.into_iter().filter(|item| {
+                let since = waiting_since(item);
+                self.shard.owns(&item.tid) && since > 0 && since < before
+            }));
+        }
+        counts.insert("stale".to_string(), stale.len() as u64);
+        log::info!(target: "job",
+            action = "list_stale",
+            rid = &ctx.rid,
+            trace = &ctx.trace.trace_id,
+            span = &ctx.trace.span_id,
+            shard = self.shard.index,
+            counts = log::as_serde!(counts);
+            "start",
+        );
+
+        for item in stale {
+            scope.check()?;
+            let tid = item.tid.to_string();
+            if !self.inbox.claim(&tid) {
+                // being handled by another job
+                *counts.entry("skipped".to_string()).or_default() += 1;
+                continue;
+            }
+            let ctx = ctx.child();
+            let res = scope.run(self.reap_item(&ctx, item)).await;
+            if res.is_ok() {
+                self.inbox.done(&tid);
+            } else {
+                self.inbox.release(&tid);
+            }
+            match res {
+                Ok(action) => *counts.entry(action.to_string()).or_default() += 1,
+                Err(err) => {
+                    *counts.entry("failed".to_string()).or_default() += 1;
+                    log::error!(target: "job",
+                        action = "reap_stale",
+                        rid = &ctx.rid,
+                        span = &ctx.trace.span_id,
+                        tid = &tid,
+                        error = err.to_string();
+                        "failed",
+                    );
+                    if err.downcast_ref::<Unavailable>().is_some() {
+                        // keep the rest for the next run.
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    // acks the item as expired, or as escalated after a task is created for the human user.
+    async fn reap_item(
+        &self,
+        ctx: &ReqContext,
+        item: NotificationOutput,
+    ) -> anyhow::Result<&'static str> {
+        let hours = (unix_ms() as i64 - waiting_since(&item)) / HOUR;
+        let (action, status, message) = match &self.reaper.escalate_to {
+            Some(uid) => {
+                let url = self.taskbase.join("/v1/task")?;
+                let _: bool = self
+                    .request(
+                        &self.taskbase,
+                        Method::POST,
+                        url,
+                        ctx,
+                        Some(&CreateTaskInput {
+                            uid: self.system_user.clone(),
+                            gid: item.gid.clone(),
+                            kind: item.kind.clone(),
+                            to: vec![uid.clone()],
+                            payload: item.payload.clone(),
+                        }),
+                    )
+                    .await?;
+                (
+                    "escalated",
+                    0,
+                    format!("escalated: not handled in {} hours", hours),
+                )
+            }
+            None => (
+                "expired",
+                -1,
+                format!("expired: not handled in {} hours", hours),
+            ),
+        };
+
+        let audit = audit::Entry::new(ctx, &item.tid).reason(&message).action(
+            "ack_todo",
+            Some(item.ack_status),
+            Some(status),
+        );
+        if let Some(uid) = &self.reaper.escalate_to {
+            self.record_digest(ctx, action, std::slice::from_ref(uid), &audit);
+        }
+        self.ack_todo(
+            ctx,
+            &AckTaskInput {
+                uid: self.system_user.clone(),
+                tid: item.tid,
+                sender: item.sender,
+                status,
+                message,
+            },
+            audit,
+        )
+        .await?;
+        Ok(action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing, Router};
+    use axum_web::erring::SuccessResponse;
+    use serde::de::IgnoredAny;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::{testing::test_rpa, Pagination};
+
+    type Acks = Arc<Mutex<Vec<AckTaskInput>>>;
+
+    async fn list(
+        to: PackObject<()>,
+        input: PackObject<Pagination>,
+    ) -> PackObject<SuccessResponse<Vec<NotificationOutput>>> {
+        let item = |age: i64| NotificationOutput {
+            tid: PackObject::Cbor(xid::new()),
+            created_at: unix_ms() as i64 - age,
+            ..Default::default()
+        };
+        // two pages, an old item and a fresh one in each, the last page has an empty token
+        let mut res = SuccessResponse::new(vec![item(HOUR * 48), item(0)]);
+        res.next_page_token = Some(PackObject::Cbor(match input.page_token {
+            None => vec![1],
+            Some(_) => vec![],
+        }));
+        to.with(res)
+    }
+
+    async fn ack(
+        State(acks): State<Acks>,
+        to: PackObject<()>,
+        input: PackObject<AckTaskInput>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        acks.lock().unwrap().push(input.unwrap());
+        to.with(SuccessResponse::new(true))
+    }
+
+    async fn ok(
+        to: PackObject<()>,
+        _input: PackObject<IgnoredAny>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        to.with(SuccessResponse::new(true))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reap_stale() {
+        let acks: Acks = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/v1/notification/list", routing::post(list))
+            .route("/v1/task/ack", routing::patch(ack))
+            .route("/v1/notification/delete", routing::post(ok))
+            .with_state(acks.clone());
+        let (rpa, _) = test_rpa(app, |cfg, _| cfg.reaper.max_age = 24 * 3600).await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let counts = rpa.reap_stale(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("total"), Some(&4));
+        assert_eq!(counts.get("stale"), Some(&2));
+        assert_eq!(counts.get("expired"), Some(&2));
+        let acks = acks.lock().unwrap();
+        assert_eq!(acks.len(), 2);
+        assert_eq!(acks[0].status, -1);
+        assert_eq!(acks[0].message, "expired: not handled in 48 hours");
+    }
+}
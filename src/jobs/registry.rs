@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    fmt,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use super::{
+    cancel::Cancel,
+    delay::{DelayQueue, Delayed},
+};
+use crate::storage::Storage;
+
+// the number of runs kept per job.
+const HISTORY_SIZE: usize = 20;
+// storage key prefix of the paused flags
+const PAUSED_KEY: &str = "jobs:paused:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Done,
+    Failed,
+    Skipped,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunRecord {
+    pub rid: String,
+    pub start: u64,   // unix milliseconds
+    pub elapsed: u64, // milliseconds
+    pub status: RunStatus,
+    pub message: String,
+    #[serde(default)]
+    pub counts: BTreeMap<String, u64>, // items handled by the run
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub schedule: String,
+    pub paused: bool,
+    pub history: Vec<RunRecord>, // newest first
+    pub delayed: Vec<Delayed>,   // in order of due time
+}
+
+struct Job {
+    schedule: String,
+    paused: bool,
+    history: VecDeque<RunRecord>,
+    delayed: Option<Arc<DelayQueue>>,
+    running: Option<(Cancel, Option<Instant>)>, // the run and its deadline
+}
+
+// Registry holds the scheduled jobs, they can be paused and resumed at runtime.
+// The paused flags are kept in the storage if persist is set.
+pub struct Registry {
+    storage: Arc<dyn Storage>,
+    persist: bool,
+    jobs: RwLock<BTreeMap<String, Job>>,
+}
+
+// NotFound is returned for a job that is not registered.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job {:?} not found", self.0)
+    }
+}
+
+impl Error for NotFound {}
+
+impl Registry {
+    pub fn new(storage: Arc<dyn Storage>, persist: bool) -> Self {
+        Self {
+            storage,
+            persist,
+            jobs: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn register(&self, name: &str, schedule: &str) {
+        let paused = self.persist
+            && self
+                .storage
+                .get_object::<bool>(&format!("{}{}", PAUSED_KEY, name))
+                .unwrap_or_default()
+                .unwrap_or_default();
+        let mut jobs = self.jobs.write().unwrap();
+        jobs.insert(
+            name.to_string(),
+            Job {
+                schedule: schedule.to_string(),
+                paused,
+                history: VecDeque::with_capacity(HISTORY_SIZE),
+                delayed: None,
+                running: None,
+            },
+        );
+    }
+
+    // shows the delay queue of the job in its info.
+    pub fn set_delay_queue(&self, name: &str, queue: Arc<DelayQueue>) {
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some(job) = jobs.get_mut(name) {
+            job.delayed = Some(queue);
+        }
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        let jobs = self.jobs.read().unwrap();
+        jobs.get(name).map_or(false, |job| job.paused)
+    }
+
+    pub fn set_paused(&self, name: &str, paused: bool) -> anyhow::Result<JobInfo> {
+        let info = {
+            let mut jobs = self.jobs.write().unwrap();
+            let job = jobs
+                .get_mut(name)
+                .ok_or_else(|| NotFound(name.to_string()))?;
+            job.paused = paused;
+            info(name, job)
+        };
+        if self.persist {
+            let key = format!("{}{}", PAUSED_KEY, name);
+            if paused {
+                self.storage.set_object(&key, &true)?;
+            } else {
+                self.storage.delete(&key)?;
+            }
+        }
+        Ok(info)
+    }
+
+    // starts a run of the job that ends by the run timeout, zero means no deadline.
+    // Returns None if the previous run is still within its deadline, a previous run
+    // beyond it is superseded.
+    pub fn begin(&self, name: &str, run_timeout: Duration) -> Option<Cancel> {
+        let now = Instant::now();
+        let cancel = Cancel::default();
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some(job) = jobs.get_mut(name) {
+            if let Some((prev, deadline)) = &job.running {
+                if deadline.map_or(true, |at| at > now) {
+                    return None;
+                }
+                prev.cancel();
+            }
+            let deadline = (!run_timeout.is_zero()).then(|| now + run_timeout);
+            job.running = Some((cancel.clone(), deadline));
+        }
+        Some(cancel)
+    }
+
+    pub fn end(&self, name: &str, cancel: &Cancel) {
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some(job) = jobs.get_mut(name) {
+            if job.running.as_ref().map_or(false, |(c, _)| c.same(cancel)) {
+                job.running = None;
+            }
+        }
+    }
+
+    pub fn record(&self, name: &str, run: RunRecord) {
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some(job) = jobs.get_mut(name) {
+            if job.history.len() == HISTORY_SIZE {
+                job.history.pop_back();
+            }
+            job.history.push_front(run);
+        }
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs = self.jobs.read().unwrap();
+        jobs.iter().map(|(name, job)| info(name, job)).collect()
+    }
+}
+
+fn info(name: &str, job: &Job) -> JobInfo {
+    JobInfo {
+        name: name.to_string(),
+        schedule: job.schedule.clone(),
+        paused: job.paused,
+        history: job.history.iter().cloned().collect(),
+        delayed: job.delayed.as_ref().map(|q| q.list()).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn pause_and_resume() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStore::default());
+        let registry = Registry::new(storage.clone(), true);
+        registry.register("publication_review", "0 * * * * * *");
+        assert!(!registry.is_paused("publication_review"));
+        assert!(registry.set_paused("unknown", true).is_err());
+
+        let info = registry.set_paused("publication_review", true).unwrap();
+        assert!(info.paused);
+        for i in 0..(HISTORY_SIZE + 2) {
+            registry.record(
+                "publication_review",
+                RunRecord {
+                    rid: i.to_string(),
+                    start: 0,
+                    elapsed: 0,
+                    status: RunStatus::Skipped,
+                    message: "paused".to_string(),
+                    counts: BTreeMap::new(),
+                },
+            );
+        }
+        let jobs = registry.list();
+        assert_eq!(jobs.len(), 1);
+
+        // skipped while the previous run is within its deadline
+        let timeout = Duration::from_secs(60);
+        let first = registry.begin("publication_review", timeout).unwrap();
+        assert!(registry.begin("publication_review", timeout).is_none());
+        registry.end("publication_review", &first);
+        let first = registry
+            .begin("publication_review", Duration::ZERO)
+            .unwrap();
+        assert!(registry.begin("publication_review", timeout).is_none());
+        registry.end("publication_review", &first);
+
+        // superseded beyond the deadline
+        let first = registry
+            .begin("publication_review", Duration::from_millis(1))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        let second = registry.begin("publication_review", timeout).unwrap();
+        assert!(first.is_cancelled());
+        registry.end("publication_review", &first);
+        assert!(registry.begin("publication_review", timeout).is_none());
+        registry.end("publication_review", &second);
+        assert!(!second.is_cancelled());
+        assert!(registry.begin("publication_review", timeout).is_some());
+        assert_eq!(jobs[0].history.len(), HISTORY_SIZE);
+        assert_eq!(jobs[0].history[0].rid, (HISTORY_SIZE + 1).to_string());
+
+        // persisted
+        let registry = Registry::new(storage.clone(), true);
+        registry.register("publication_review", "0 * * * * * *");
+        assert!(registry.is_paused("publication_review"));
+        registry.set_paused("publication_review", false).unwrap();
+        let registry = Registry::new(storage.clone(), true);
+        registry.register("publication_review", "0 * * * * * *");
+        assert!(!registry.is_paused("publication_review"));
+
+        // not persisted
+        let registry = Registry::new(storage.clone(), false);
+        registry.register("publication_review", "0 * * * * * *");
+        registry.set_paused("publication_review", true).unwrap();
+        let registry = Registry::new(storage, true);
+        registry.register("publication_review", "0 * * * * * *");
+        assert!(!registry.is_paused("publication_review"));
+    }
+}
@@ -0,0 +1,247 @@
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+use super::PublicationOutput;
+use crate::conf;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Reject,
+    Escalate,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Rule {
+    ContentLength {
+        min: Option<usize>,
+        max: Option<usize>,
+        action: Action,
+    },
+    Language {
+        allow: Vec<String>,
+        action: Action,
+    },
+    Version {
+        min: Option<i16>,
+        max: Option<i16>,
+        action: Action,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Approve,
+    Reject(String),
+    Escalate(String),
+}
+
+impl Decision {
+    fn from_action(action: Action, reason: String) -> Self {
+        match action {
+            Action::Reject => Decision::Reject(reason),
+            Action::Escalate => Decision::Escalate(reason),
+        }
+    }
+
+    // status for AckTaskInput
+    pub fn ack_status(&self) -> i8 {
+        match self {
+            Decision::Approve => 1,
+            Decision::Reject(_) => -1,
+            Decision::Escalate(_) => 0,
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Approve => write!(f, "approved"),
+            Decision::Reject(reason) => write!(f, "rejected: {}", reason),
+            Decision::Escalate(reason) => write!(f, "escalated: {}", reason),
+        }
+    }
+}
+
+// Rules are checked in order, the first violated rule decides the outcome.
+// A publication that passes all rules is approved.
+pub struct Rules {
+    rules: Vec<Rule>,
+    languages: Vec<Vec<isolang::Language>>,
+}
+
+impl Rules {
+    pub fn new(cfg: &conf::Review) -> anyhow::Result<Self> {
+        let mut languages = Vec::with_capacity(cfg.rules.len());
+        for rule in &cfg.rules {
+            let mut list = Vec::new();
+            if let Rule::Language { allow, .. } = rule {
+                for lang in allow {
+                    list.push(parse_language(lang)?);
+                }
+            }
+            languages.push(list);
+        }
+
+        Ok(Self {
+            rules: cfg.rules.clone(),
+            languages,
+        })
+    }
+
+    // returns true if a rule escalates to human reviewers.
+    pub fn escalates(&self) -> bool {
+        self.rules.iter().any(|rule| {
+            let action = match rule {
+                Rule::ContentLength { action, .. } => action,
+                Rule::Language { action, .. } => action,
+                Rule::Version { action, .. } => action,
+            };
+            *action == Action::Escalate
+        })
+    }
+
+    pub fn evaluate(&self, publ: &PublicationOutput) -> Decision {
+        for (i, rule) in self.rules.iter().enumerate() {
+            match rule {
+                Rule::ContentLength { min, max, action } => {
+                    if let Some(min) = min {
+                        if publ.content_length < *min {
+                            return Decision::from_action(
+                                *action,
+                                format!(
+                                    "content length {} is less than {}",
+                                    publ.content_length, min
+                                ),
+                            );
+                        }
+                    }
+                    if let Some(max) = max {
+                        if publ.content_length > *max {
+                            return Decision::from_action(
+                                *action,
+                                format!(
+                                    "content length {} is greater than {}",
+                                    publ.content_length, max
+                                ),
+                            );
+                        }
+                    }
+                }
+                Rule::Language { action, .. } => {
+                    let allowed = match parse_language(&publ.language) {
+                        Ok(lang) => self.languages[i].contains(&lang),
+                        Err(_) => false,
+                    };
+                    if !allowed {
+                        return Decision::from_action(
+                            *action,
+                            format!("language {:?} is not allowed", publ.language),
+                        );
+                    }
+                }
+                Rule::Version { min, max, action } => {
+                    if let Some(min) = min {
+                        if publ.version < *min {
+                            return Decision::from_action(
+                                *action,
+                                format!("version {} is less than {}", publ.version, min),
+                            );
+                        }
+                    }
+                    if let Some(max) = max {
+                        if publ.version > *max {
+                            return Decision::from_action(
+                                *action,
+                                format!("version {} is greater than {}", publ.version, max),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        Decision::Approve
+    }
+}
+
+pub fn parse_language(s: &str) -> anyhow::Result<isolang::Language> {
+    isolang::Language::from_str(s.to_ascii_lowercase().as_str())
+        .map_err(|err| anyhow::anyhow!("invalid language {:?}, {}", s, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn review(rules: Vec<Rule>) -> conf::Review {
+        conf::Review {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn evaluate_rules() {
+        let rules = Rules::new(&review(vec![
+            Rule::ContentLength {
+                min: Some(10),
+                max: Some(1000),
+                action: Action::Reject,
+            },
+            Rule::Language {
+                allow: vec!["en".to_string(), "zho".to_string()],
+                action: Action::Escalate,
+            },
+            Rule::Version {
+                min: Some(1),
+                max: None,
+                action: Action::Reject,
+            },
+        ]))
+        .unwrap();
+
+        let mut publ = PublicationOutput {
+            language: "eng".to_string(),
+            version: 1,
+            content_length: 100,
+            ..Default::default()
+        };
+        assert_eq!(rules.evaluate(&publ), Decision::Approve);
+
+        publ.content_length = 5;
+        let decision = rules.evaluate(&publ);
+        assert_eq!(decision.ack_status(), -1);
+        assert_eq!(
+            decision.to_string(),
+            "rejected: content length 5 is less than 10"
+        );
+
+        publ.content_length = 100;
+        publ.language = "fra".to_string();
+        let decision = rules.evaluate(&publ);
+        assert_eq!(decision.ack_status(), 0);
+        assert_eq!(
+            decision.to_string(),
+            r#"escalated: language "fra" is not allowed"#
+        );
+
+        publ.language = "zh".to_string();
+        publ.version = 0;
+        assert_eq!(
+            rules.evaluate(&publ),
+            Decision::Reject("version 0 is less than 1".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_language() {
+        assert!(Rules::new(&review(vec![Rule::Language {
+            allow: vec!["xx-yy".to_string()],
+            action: Action::Reject,
+        }]))
+        .is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::conf;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// Shard picks the todo items of a replica: an item belongs to the replica
+// whose index is the FNV-1a hash of its task id modulo the replica count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Shard {
+    pub fn new(cfg: &conf::Shard) -> anyhow::Result<Self> {
+        let count = cfg.count.max(1);
+        let index = match cfg.index {
+            Some(index) => index,
+            None if count == 1 => 0,
+            None => {
+                let host = std::env::var("HOSTNAME").unwrap_or_default();
+                ordinal(&host).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "shard index is not configured and not in hostname {:?}",
+                        host
+                    )
+                })?
+            }
+        };
+        if index >= count {
+            anyhow::bail!("shard index {} is out of count {}", index, count);
+        }
+        Ok(Self { index, count })
+    }
+
+    pub fn owns(&self, tid: &xid::Id) -> bool {
+        self.count <= 1
+            || fnv1a(tid.to_string().as_bytes()) % self.count as u64 == self.index as u64
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(FNV_OFFSET, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+// ordinal of a StatefulSet pod, e.g. 2 for "yiwen-rpa-2".
+fn ordinal(host: &str) -> Option<u32> {
+    host.rsplit_once('-')?.1.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owns() {
+        assert_eq!(fnv1a(b""), FNV_OFFSET);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        let shards: Vec<Shard> = (0..3)
+            .map(|i| {
+                Shard::new(&conf::Shard {
+                    index: Some(i),
+                    count: 3,
+                })
+                .unwrap()
+            })
+            .collect();
+        let mut counts = [0; 3];
+        for _ in 0..3000 {
+            let tid = xid::new();
+            let owners: Vec<&Shard> = shards.iter().filter(|s| s.owns(&tid)).collect();
+            assert_eq!(owners.len(), 1);
+            counts[owners[0].index as usize] += 1;
+        }
+        for n in counts {
+            assert!(n > 800, "{:?}", counts);
+        }
+
+        assert!(Shard::new(&conf::Shard::default())
+            .unwrap()
+            .owns(&xid::new()));
+        assert!(Shard::new(&conf::Shard {
+            index: Some(3),
+            count: 3
+        })
+        .is_err());
+        assert_eq!(ordinal("yiwen-rpa-2"), Some(2));
+        assert_eq!(ordinal("localhost"), None);
+    }
+}
@@ -0,0 +1,70 @@
+use axum::Router;
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+use super::RPA;
+use crate::{conf, logger::Levels};
+
+// serves the stub of the upstreams on a local port.
+pub fn serve(app: Router) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    addr
+}
+
+// a config with both upstreams at base and the optional jobs disabled, it does
+// not depend on config/default.toml.
+pub fn test_conf(base: &str) -> conf::Conf {
+    conf::Conf {
+        env: "test".to_string(),
+        log: conf::Log {
+            level: "info".to_string(),
+            targets: BTreeMap::new(),
+            files: vec![],
+        },
+        server: conf::Server {
+            port: 0,
+            cert_file: "".to_string(),
+            key_file: "".to_string(),
+            graceful_shutdown: 0,
+            admins: vec![],
+            notifiers: vec![],
+        },
+        base: conf::Base {
+            taskbase: base.to_string(),
+            writing: base.to_string(),
+        },
+        upstreams: BTreeMap::new(),
+        breaker: conf::Breaker::default(),
+        review: conf::Review::default(),
+        moderation: conf::Moderation::default(),
+        otlp: conf::Otlp::default(),
+        audit: conf::Audit::default(),
+        storage: conf::Storage::default(),
+        lease: conf::Lease::default(),
+        shard: conf::Shard::default(),
+        jobs: conf::Jobs::default(),
+        reaper: conf::Reaper::default(),
+        translation: conf::Translation::default(),
+        digest: conf::Digest::default(),
+        webhook: conf::Webhook::default(),
+    }
+}
+
+// returns an RPA whose upstreams are served by the stub, setup adjusts the config
+// with the address of the stub.
+pub async fn test_rpa(
+    app: Router,
+    setup: impl FnOnce(&mut conf::Conf, SocketAddr),
+) -> (RPA, SocketAddr) {
+    let addr = serve(app);
+    let mut cfg = test_conf(&format!("http://{}", addr));
+    setup(&mut cfg, addr);
+    let levels = Arc::new(Levels::new(&cfg.log).unwrap());
+    let state = cfg.new_app_state(levels).await.unwrap();
+    (RPA::new(cfg, &state), addr)
+}
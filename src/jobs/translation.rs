@@ -0,0 +1,358 @@
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
+
+use axum_web::{
+    context::ReqContext,
+    object::{cbor_to_vec, PackObject},
+};
+
+use super::{
+    breaker::Unavailable,
+    cancel::{Cancelled, Scope},
+    review::parse_language,
+    CreateTaskInput, PublicationInput, RPA,
+};
+use crate::conf;
+
+const TRANSLATE_KIND: &str = "translate.publication";
+// storage key prefixes of the translation job
+const TRANSLATE_PENDING: &str = "translate:pending:";
+const TRANSLATE_FAILURES: &str = "translate:failures:";
+
+// Translation creates follow-up translation tasks for the approved publications.
+pub struct Translation {
+    languages: Vec<isolang::Language>,
+    translators: Vec<PackObject<xid::Id>>,
+}
+
+impl Translation {
+    pub fn new(cfg: &conf::Translation, format: &PackObject<()>) -> anyhow::Result<Self> {
+        let mut translators = Vec::with_capacity(cfg.translators.len());
+        for uid in &cfg.translators {
+            let id = xid::Id::from_str(uid)
+                .map_err(|err| anyhow::anyhow!("invalid translator {:?}, {}", uid, err))?;
+            translators.push(format.with(id));
+        }
+        if !cfg.languages.is_empty() && translators.is_empty() {
+            anyhow::bail!("translators are required for languages");
+        }
+        let mut languages: Vec<isolang::Language> = Vec::with_capacity(cfg.languages.len());
+        for lang in &cfg.languages {
+            if !languages.contains(lang) {
+                languages.push(**lang);
+            }
+        }
+        Ok(Self {
+            languages,
+            translators,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.languages.is_empty()
+    }
+}
+
+// a publication waiting for its translation tasks, languages are ISO 639-3 codes.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PendingTranslation {
+    pub source: PublicationInput,
+    pub languages: Vec<String>,
+}
+
+// the payload of a translation task.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TranslationTask {
+    pub gid: PackObject<xid::Id>,
+    pub cid: PackObject<xid::Id>,
+    pub language: String,
+    pub version: i16,
+    pub to_language: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PublicationLanguageOutput {
+    pub language: String,
+}
+
+fn pending_key(publ: &PublicationInput) -> String {
+    format!(
+        "{}{}:{}:{}:{}",
+        TRANSLATE_PENDING, *publ.gid, *publ.cid, publ.language, publ.version
+    )
+}
+
+impl RPA {
+    // queues the translation tasks of an approved publication for the translation job.
+    pub(super) fn request_translation(&self, ctx: &ReqContext, source: PublicationInput) {
+        if !self.translation.enabled() {
+            return;
+        }
+        let source_lang = parse_language(&source.language).ok();
+        let languages: Vec<String> = self
+            .translation
+            .languages
+            .iter()
+            .filter(|lang| Some(**lang) != source_lang)
+            .map(|lang| lang.to_639_3().to_string())
+            .collect();
+        if languages.is_empty() {
+            return;
+        }
+
+        let key = pending_key(&source);
+        if let Err(err) = self
+            .storage
+            .set_object(&key, &PendingTranslation { source, languages })
+        {
+            log::warn!(target: "job",
+                action = "request_translation",
+                rid = &ctx.rid,
+                key = &key,
+                error = err.to_string();
+                "",
+            );
+        }
+    }
+
+    // creates the translation tasks of the pending publications.
+    pub(super) async fn translate(
+        &self,
+        ctx: &ReqContext,
+        scope: &Scope,
+    ) -> anyhow::Result<BTreeMap<String, u64>> {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        let keys = self.storage.keys(TRANSLATE_PENDING)?;
+        counts.insert("pending".to_string(), keys.len() as u64);
+        for key in keys {
+            scope.check()?;
+            let pending: PendingTranslation = match self.storage.get_object(&key)? {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let ctx = ctx.child();
+            let failures_key = format!("{}{}", TRANSLATE_FAILURES, &key[TRANSLATE_PENDING.len()..]);
+            match scope
+                .run(self.translate_item(&ctx, &key, pending, &mut counts))
+                .await
+            {
+                Ok(_) => {
+                    self.clear_failures(&ctx, &failures_key);
+                }
+                Err(err) if err.downcast_ref::<Cancelled>().is_some() => {
+                    // the languages left are kept for the next run.
+                    log::warn!(target: "job",
+                        action = "translate",
+                        rid = &ctx.rid,
+                        key = &key,
+                        error = err.to_string();
+                        "stopped",
+                    );
+                }
+                Err(err) if err.downcast_ref::<Unavailable>().is_some() => {
+                    log::warn!(target: "job",
+                        action = "translate",
+                        rid = &ctx.rid,
+                        key = &key,
+                        error = err.to_string();
+                        "stopped",
+                    );
+                    break;
+                }
+                Err(err) => {
+                    *counts.entry("failed".to_string()).or_default() += 1;
+                    let failures = self
+                        .storage
+                        .incr(&failures_key, 1)
+                        .unwrap_or(self.max_failures);
+                    log::error!(target: "job",
+                        action = "translate",
+                        rid = &ctx.rid,
+                        key = &key,
+                        failures = failures,
+                        error = err.to_string();
+                        "failed",
+                    );
+                    if failures >= self.max_failures {
+                        // give up the publication
+                        self.clear_failures(&ctx, &failures_key);
+                        let _ = self.storage.delete(&key);
+                    }
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    // creates a task for every language that is not translated yet, the languages
+    // left are kept so that a retry does not create a task twice.
+    async fn translate_item(
+        &self,
+        ctx: &ReqContext,
+        key: &str,
+        mut pending: PendingTranslation,
+        counts: &mut BTreeMap<String, u64>,
+    ) -> anyhow::Result<()> {
+        let existing: Vec<isolang::Language> = self
+            .list_publication_languages(ctx, &pending.source)
+            .await?
+            .into_iter()
+            .filter_map(|publ| parse_language(&publ.language).ok())
+            .collect();
+
+        while let Some(lang) = pending.languages.first().cloned() {
+            let exists = parse_language(&lang)
+                .map(|lang| existing.contains(&lang))
+                .unwrap_or(false);
+            if exists {
+                *counts.entry("skipped".to_string()).or_default() += 1;
+            } else {
+                let payload = cbor_to_vec(&TranslationTask {
+                    gid: pending.source.gid.clone(),
+                    cid: pending.source.cid.clone(),
+                    language: pending.source.language.clone(),
+                    version: pending.source.version,
+                    to_language: lang.clone(),
+                })?;
+                let url = self.taskbase.join("/v1/task")?;
+                let _: bool = self
+                    .request(
+                        &self.taskbase,
+                        Method::POST,
+                        url,
+                        ctx,
+                        Some(&CreateTaskInput {
+                            uid: self.system_user.clone(),
+                            gid: pending.source.gid.clone(),
+                            kind: TRANSLATE_KIND.to_string(),
+                            to: self.translation.translators.clone(),
+                            payload: self.taskbase.format.with(payload),
+                        }),
+                    )
+                    .await?;
+                *counts.entry("created".to_string()).or_default() += 1;
+                log::info!(target: "job",
+                    action = "create_translation",
+                    rid = &ctx.rid,
+                    gid = pending.source.gid.to_string(),
+                    cid = pending.source.cid.to_string(),
+                    language = &lang;
+                    "",
+                );
+            }
+
+            pending.languages.remove(0);
+            if !pending.languages.is_empty() {
+                self.storage.set_object(key, &pending)?;
+            }
+        }
+        self.storage.delete(key)?;
+        Ok(())
+    }
+
+    async fn list_publication_languages(
+        &self,
+        ctx: &ReqContext,
+        input: &PublicationInput,
+    ) -> anyhow::Result<Vec<PublicationLanguageOutput>> {
+        let mut url = self.writing.join("/v1/publication/list")?;
+        url.query_pairs_mut()
+            .append_pair("gid", &input.gid.to_string())
+            .append_pair("cid", &input.cid.to_string())
+            .append_pair("fields", "language");
+        self.request::<(), Vec<PublicationLanguageOutput>>(
+            &self.writing,
+            Method::GET,
+            url,
+            ctx,
+            None,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing, Router};
+    use axum_web::erring::SuccessResponse;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::testing::test_rpa;
+
+    type Tasks = Arc<Mutex<Vec<CreateTaskInput>>>;
+
+    async fn list() -> PackObject<SuccessResponse<Vec<PublicationLanguageOutput>>> {
+        let publ = |language: &str| PublicationLanguageOutput {
+            language: language.to_string(),
+        };
+        PackObject::Cbor(SuccessResponse::new(vec![publ("eng"), publ("zh")]))
+    }
+
+    async fn create_task(
+        State(tasks): State<Tasks>,
+        to: PackObject<()>,
+        input: PackObject<CreateTaskInput>,
+    ) -> PackObject<SuccessResponse<bool>> {
+        tasks.lock().unwrap().push(input.unwrap());
+        to.with(SuccessResponse::new(true))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn translate() {
+        let tasks: Tasks = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/v1/publication/list", routing::get(list))
+            .route("/v1/task", routing::post(create_task))
+            .with_state(tasks.clone());
+        let (rpa, _) = test_rpa(app, |cfg, _| {
+            cfg.translation.languages = vec![
+                PackObject::Cbor(isolang::Language::Zho),
+                PackObject::Cbor(isolang::Language::Eng),
+                PackObject::Cbor(isolang::Language::Fra),
+                PackObject::Cbor(isolang::Language::Fra),
+            ];
+            cfg.translation.translators = vec![xid::new().to_string()];
+        })
+        .await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        // eng is the source language, zho is translated already
+        rpa.request_translation(
+            &ctx,
+            PublicationInput {
+                language: "eng".to_string(),
+                version: 1,
+                ..Default::default()
+            },
+        );
+        let counts = rpa.translate(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("pending"), Some(&1));
+        assert_eq!(counts.get("skipped"), Some(&1));
+        assert_eq!(counts.get("created"), Some(&1));
+        {
+            let tasks = tasks.lock().unwrap();
+            assert_eq!(tasks.len(), 1);
+            assert_eq!(tasks[0].kind, TRANSLATE_KIND);
+            let task: TranslationTask =
+                axum_web::object::cbor_from_slice(&tasks[0].payload).unwrap();
+            assert_eq!(task.to_language, "fra");
+        }
+
+        // done
+        let counts = rpa.translate(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("pending"), Some(&0));
+        assert_eq!(tasks.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn translators_required() {
+        let mut cfg = conf::Translation::default();
+        assert!(Translation::new(&cfg, &PackObject::Cbor(())).is_ok());
+        cfg.languages = vec![PackObject::Cbor(isolang::Language::Eng)];
+        assert!(Translation::new(&cfg, &PackObject::Cbor(())).is_err());
+        cfg.translators = vec![xid::new().to_string()];
+        assert!(Translation::new(&cfg, &PackObject::Cbor(())).is_ok());
+    }
+}
@@ -0,0 +1,364 @@
+use reqwest::{header, Client};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Write};
+
+use axum_web::{
+    context::{unix_ms, ReqContext},
+    object::cbor_to_vec,
+};
+
+use super::{
+    cancel::{Cancelled, Scope},
+    RPA, X_REQUEST_ID,
+};
+use crate::{audit, conf};
+
+// storage key prefix of the events waiting for delivery, in order of the sequence
+const OUTBOX: &str = "outbox:";
+const OUTBOX_SEQ: &str = "webhook:seq";
+// the longest wait between two attempts, in milliseconds
+const MAX_RETRY_INTERVAL: u64 = 3600 * 1000;
+static X_WEBHOOK_ID: header::HeaderName = header::HeaderName::from_static("x-webhook-id");
+static X_WEBHOOK_TIMESTAMP: header::HeaderName =
+    header::HeaderName::from_static("x-webhook-timestamp");
+static X_WEBHOOK_SIGNATURE: header::HeaderName =
+    header::HeaderName::from_static("x-webhook-signature");
+
+// the event POSTed to the webhook after a review decision is applied.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct DecisionEvent {
+    pub id: String, // the same for a decision that is emitted again
+    pub ts: u64,    // unix milliseconds
+    pub rid: String,
+    pub tid: String,
+    pub gid: String,
+    pub cid: String,
+    pub language: String,
+    pub version: i16,
+    pub decision: String, // "approve", "reject" or "escalate"
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Outboxed {
+    event: DecisionEvent,
+    attempts: u32,
+    next_at: u64, // unix milliseconds
+}
+
+// signs the timestamp and the body with HMAC-SHA256, returns "sha256=<hex>".
+pub fn sign(secret: &str, ts: u64, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut ctx = hmac::Context::with_key(&key);
+    ctx.update(ts.to_string().as_bytes());
+    ctx.update(b".");
+    ctx.update(body);
+    let mut sig = String::from("sha256=");
+    for b in ctx.sign().as_ref() {
+        let _ = write!(sig, "{:02x}", b);
+    }
+    sig
+}
+
+// Webhook delivers the decision events to an endpoint in order. Events are kept
+// in the outbox of the storage until they are delivered or run out of retries.
+pub struct Webhook {
+    client: Option<Client>,
+    endpoint: Option<reqwest::Url>,
+    secret: String,
+    format: conf::Format,
+    max_retries: u32,
+    retry_interval: u64, // milliseconds, doubled on every failure
+}
+
+impl Webhook {
+    pub fn new(cfg: &conf::Webhook) -> anyhow::Result<Self> {
+        let (client, endpoint) = if cfg.endpoint.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(super::new_external_client()?),
+                Some(reqwest::Url::parse(&cfg.endpoint)?),
+            )
+        };
+        Ok(Self {
+            client,
+            endpoint,
+            secret: cfg.secret.clone(),
+            format: cfg.format,
+            max_retries: cfg.max_retries,
+            retry_interval: cfg.retry_interval * 1000,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    async fn post(&self, ctx: &ReqContext, event: &DecisionEvent) -> anyhow::Result<()> {
+        let (client, endpoint) = match (&self.client, &self.endpoint) {
+            (Some(client), Some(endpoint)) => (client, endpoint),
+            _ => anyhow::bail!("webhook is not configured"),
+        };
+        let (content_type, body) = match self.format {
+            conf::Format::Cbor => ("application/cbor", cbor_to_vec(event)?),
+            conf::Format::Json => ("application/json", serde_json::to_vec(event)?),
+        };
+        let ts = unix_ms() / 1000;
+        let mut req = client
+            .post(endpoint.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .header(&X_REQUEST_ID, &ctx.rid)
+            .header(&X_WEBHOOK_ID, &event.id)
+            .header(&X_WEBHOOK_TIMESTAMP, ts.to_string());
+        if !self.secret.is_empty() {
+            req = req.header(&X_WEBHOOK_SIGNATURE, sign(&self.secret, ts, &body));
+        }
+        let res = req.body(body).send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            anyhow::bail!("webhook responded {}, {}", status, body);
+        }
+        Ok(())
+    }
+}
+
+impl RPA {
+    // keeps the decision in the outbox for the webhook job.
+    pub(super) fn emit_decision(&self, ctx: &ReqContext, decision: &str, entry: &audit::Entry) {
+        if !self.webhook.enabled() {
+            return;
+        }
+        let event = DecisionEvent {
+            id: event_id(entry, decision),
+            ts: unix_ms(),
+            rid: ctx.rid.clone(),
+            tid: entry.tid.clone(),
+            gid: entry.gid.clone(),
+            cid: entry.cid.clone(),
+            language: entry.language.clone(),
+            version: entry.version,
+            decision: decision.to_string(),
+            reason: entry.reason.clone(),
+        };
+        let item = Outboxed {
+            event,
+            attempts: 0,
+            next_at: 0,
+        };
+        let res = self.storage.incr(OUTBOX_SEQ, 1).and_then(|seq| {
+            self.storage
+                .set_object(&format!("{}{:020}", OUTBOX, seq), &item)
+        });
+        if let Err(err) = res {
+            log::error!(target: "job",
+                action = "emit_decision",
+                rid = &ctx.rid,
+                id = &item.event.id,
+                error = err.to_string();
+                "event is lost",
+            );
+        }
+    }
+
+    // delivers the events in the outbox in order, a failed event holds the rest back
+    // until its next attempt.
+    pub(super) async fn deliver_outbox(
+        &self,
+        ctx: &ReqContext,
+        scope: &Scope,
+    ) -> anyhow::Result<BTreeMap<String, u64>> {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        if !self.webhook.enabled() {
+            return Ok(counts);
+        }
+
+        let keys = self.storage.keys(OUTBOX)?;
+        counts.insert("pending".to_string(), keys.len() as u64);
+        for key in keys {
+            scope.check()?;
+            let mut item: Outboxed = match self.storage.get_object(&key)? {
+                Some(item) => item,
+                None => continue,
+            };
+            if item.next_at > unix_ms() {
+                break;
+            }
+
+            let ctx = ctx.child();
+            match scope.run(self.webhook.post(&ctx, &item.event)).await {
+                Ok(_) => {
+                    *counts.entry("delivered".to_string()).or_default() += 1;
+                    self.storage.delete(&key)?;
+                }
+                Err(err) if err.downcast_ref::<Cancelled>().is_some() && scope.check().is_err() => {
+                    // the run is stopped, it is not an attempt.
+                    return Err(err);
+                }
+                Err(err) => {
+                    item.attempts += 1;
+                    log::error!(target: "job",
+                        action = "deliver_outbox",
+                        rid = &ctx.rid,
+                        id = &item.event.id,
+                        attempts = item.attempts,
+                        error = err.to_string();
+                        "failed",
+                    );
+                    if item.attempts > self.webhook.max_retries {
+                        *counts.entry("dropped".to_string()).or_default() += 1;
+                        self.storage.delete(&key)?;
+                        continue;
+                    }
+
+                    *counts.entry("failed".to_string()).or_default() += 1;
+                    let wait = self
+                        .webhook
+                        .retry_interval
+                        .saturating_mul(1 << (item.attempts - 1).min(16))
+                        .min(MAX_RETRY_INTERVAL);
+                    item.next_at = unix_ms() + wait;
+                    self.storage.set_object(&key, &item)?;
+                    break;
+                }
+            }
+        }
+        Ok(counts)
+    }
+}
+
+// derives the event id from the task, the publication and the decision, so that
+// a decision that is emitted again after its ack failed can be deduplicated.
+fn event_id(entry: &audit::Entry, decision: &str) -> String {
+    let key = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        entry.tid, entry.gid, entry.cid, entry.language, entry.version, decision
+    );
+    let hash = digest::digest(&digest::SHA256, key.as_bytes());
+    let mut buf = [0u8; 16];
+    buf.copy_from_slice(&hash.as_ref()[..16]);
+    uuid::Uuid::new_v8(buf).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing, Router,
+    };
+    use axum_web::object::{cbor_from_slice, PackObject};
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use super::super::testing::test_rpa;
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        events: Arc<Mutex<Vec<DecisionEvent>>>,
+        failures: Arc<Mutex<u32>>, // requests to fail
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        {
+            let mut failures = receiver.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        }
+        let header =
+            |name: &header::HeaderName| headers.get(name).unwrap().to_str().unwrap().to_string();
+        let ts: u64 = header(&X_WEBHOOK_TIMESTAMP).parse().unwrap();
+        if header(&X_WEBHOOK_SIGNATURE) != sign("secret", ts, &body) {
+            return StatusCode::UNAUTHORIZED;
+        }
+        let event: DecisionEvent = cbor_from_slice(&body).unwrap();
+        assert_eq!(header(&X_WEBHOOK_ID), event.id);
+        receiver.events.lock().unwrap().push(event);
+        StatusCode::NO_CONTENT
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[should_panic(expected = "the outbox needs a [storage] path")]
+    async fn outbox_needs_storage() {
+        test_rpa(Router::new(), |cfg, addr| {
+            cfg.webhook.endpoint = format!("http://{}/events", addr);
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deliver_outbox() {
+        let receiver = Receiver::default();
+        let app = Router::new()
+            .route("/events", routing::post(receive))
+            .with_state(receiver.clone());
+        let dir = std::env::temp_dir().join(format!("rpa-outbox-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("storage.cbor");
+        let (rpa, _) = test_rpa(app, |cfg, addr| {
+            cfg.storage.path = path.to_str().unwrap().to_string();
+            cfg.webhook.endpoint = format!("http://{}/events", addr);
+            cfg.webhook.secret = "secret".to_string();
+            cfg.webhook.format = conf::Format::Cbor;
+            cfg.webhook.max_retries = 1;
+            cfg.webhook.retry_interval = 0;
+        })
+        .await;
+
+        let ctx = ReqContext::new("rid", xid::Id::default(), 0);
+        let entry = audit::Entry::new(&ctx, &PackObject::Cbor(xid::new()))
+            .publication(
+                &PackObject::Cbor(xid::new()),
+                &PackObject::Cbor(xid::new()),
+                "eng",
+                1,
+            )
+            .reason("approved");
+        rpa.emit_decision(&ctx, "approve", &entry);
+        rpa.emit_decision(&ctx, "reject", &entry.clone().reason("too short"));
+        // emitted again after a failed ack
+        rpa.emit_decision(&ctx, "approve", &entry);
+
+        // the first event fails and holds the second one back
+        *receiver.failures.lock().unwrap() = 1;
+        let counts = rpa.deliver_outbox(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("pending"), Some(&3));
+        assert_eq!(counts.get("failed"), Some(&1));
+        assert_eq!(counts.get("delivered"), None);
+
+        let counts = rpa.deliver_outbox(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("delivered"), Some(&3));
+        {
+            let events = receiver.events.lock().unwrap();
+            assert_eq!(events.len(), 3);
+            assert_eq!(events[0].decision, "approve");
+            assert_eq!(events[0].gid, entry.gid);
+            assert_eq!(events[1].decision, "reject");
+            assert_eq!(events[1].reason, "too short");
+            assert_ne!(events[0].id, events[1].id);
+            assert_eq!(events[0].id, events[2].id);
+        }
+
+        // dropped after the retries
+        rpa.emit_decision(&ctx, "escalate", &entry);
+        *receiver.failures.lock().unwrap() = 2;
+        let counts = rpa.deliver_outbox(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("failed"), Some(&1));
+        let counts = rpa.deliver_outbox(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("dropped"), Some(&1));
+        let counts = rpa.deliver_outbox(&ctx, &rpa.scope(None)).await.unwrap();
+        assert_eq!(counts.get("pending"), Some(&0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use log::{Level, LevelFilter, Metadata, Record};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+use structured_logger::{async_json, json, Builder};
+
+use crate::conf;
+
+// Levels is the level per log target that can be changed at runtime. A target
+// matches itself and its "::" children, the longest match wins, e.g. "hyper"
+// matches "hyper::proto::h1". Targets without a match use the default level.
+pub struct Levels {
+    inner: RwLock<LevelsInner>,
+}
+
+struct LevelsInner {
+    default: LevelFilter,
+    targets: BTreeMap<String, LevelFilter>,
+}
+
+impl Levels {
+    pub fn new(cfg: &conf::Log) -> anyhow::Result<Self> {
+        let mut targets = BTreeMap::new();
+        for (target, level) in &cfg.targets {
+            targets.insert(target.clone(), parse_level(level)?);
+        }
+        Ok(Self {
+            inner: RwLock::new(LevelsInner {
+                default: parse_level(&cfg.level)?,
+                targets,
+            }),
+        })
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        let inner = self.inner.read().unwrap();
+        let mut filter = inner.default;
+        let mut matched = 0;
+        for (t, f) in &inner.targets {
+            if t.len() > matched
+                && target.starts_with(t.as_str())
+                && (target.len() == t.len() || target[t.len()..].starts_with("::"))
+            {
+                filter = *f;
+                matched = t.len();
+            }
+        }
+        level <= filter
+    }
+
+    // sets the level of the target, or the default level if target is None.
+    // An empty level removes the target's level.
+    pub fn set(&self, target: Option<&str>, level: &str) -> anyhow::Result<()> {
+        {
+            let mut inner = self.inner.write().unwrap();
+            match target {
+                None => inner.default = parse_level(level)?,
+                Some(target) if level.is_empty() => {
+                    inner.targets.remove(target);
+                }
+                Some(target) => {
+                    inner
+                        .targets
+                        .insert(target.to_string(), parse_level(level)?);
+                }
+            }
+        }
+        log::set_max_level(self.max());
+        Ok(())
+    }
+
+    // returns the default level and the level per target.
+    pub fn list(&self) -> (String, BTreeMap<String, String>) {
+        let inner = self.inner.read().unwrap();
+        (
+            inner.default.to_string().to_lowercase(),
+            inner
+                .targets
+                .iter()
+                .map(|(t, f)| (t.clone(), f.to_string().to_lowercase()))
+                .collect(),
+        )
+    }
+
+    fn max(&self) -> LevelFilter {
+        let inner = self.inner.read().unwrap();
+        inner
+            .targets
+            .values()
+            .fold(inner.default, |max, f| max.max(*f))
+    }
+}
+
+fn parse_level(level: &str) -> anyhow::Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| anyhow::anyhow!("invalid log level {:?}", level))
+}
+
+struct Logger {
+    levels: Arc<Levels>,
+    inner: Box<dyn log::Log>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.levels.enabled(metadata.target(), metadata.level())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// init sets the global logger, logs of the targets in cfg.files are written to
+// the rotating files, others to stdout.
+pub fn init(cfg: &conf::Log) -> anyhow::Result<Arc<Levels>> {
+    let levels = Arc::new(Levels::new(cfg)?);
+    // levels are checked by Logger
+    let mut builder = Builder::with_level("trace");
+    for f in &cfg.files {
+        let file = RotatingFile::new(&f.path, f.max_size, f.max_files)
+            .map_err(|err| anyhow::anyhow!("open log file {:?} failed, {}", f.path, err))?;
+        builder = builder.with_target_writer(&f.targets, json::new_writer(file));
+    }
+    let builder = builder.with_default_writer(async_json::new_writer(tokio::io::stdout()));
+
+    log::set_boxed_logger(Box::new(Logger {
+        levels: levels.clone(),
+        inner: Box::new(builder.build()),
+    }))?;
+    log::set_max_level(levels.max());
+    Ok(levels)
+}
+
+// RotatingFile appends to the file at path and renames it to "{path}.1" when it
+// grows beyond max_size bytes, older files are shifted to "{path}.2" and so on,
+// up to max_files.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated(i);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let levels = Levels::new(&conf::Log {
+            level: "info".to_string(),
+            targets: BTreeMap::from([
+                ("job".to_string(), "debug".to_string()),
+                ("hyper".to_string(), "warn".to_string()),
+                ("hyper::proto".to_string(), "error".to_string()),
+            ]),
+            files: vec![],
+        })
+        .unwrap();
+
+        assert!(levels.enabled("api", Level::Info));
+        assert!(!levels.enabled("api", Level::Debug));
+        assert!(levels.enabled("job", Level::Debug));
+        assert!(!levels.enabled("jobs", Level::Debug));
+        assert!(!levels.enabled("hyper::client", Level::Info));
+        assert!(levels.enabled("hyper::client", Level::Warn));
+        assert!(!levels.enabled("hyper::proto::h1", Level::Warn));
+        assert_eq!(levels.max(), LevelFilter::Debug);
+
+        levels.set(Some("api"), "trace").unwrap();
+        assert!(levels.enabled("api", Level::Trace));
+        levels.set(Some("job"), "").unwrap();
+        assert!(!levels.enabled("job", Level::Debug));
+        levels.set(None, "error").unwrap();
+        assert!(!levels.enabled("job", Level::Info));
+        assert!(levels.set(None, "noisy").is_err());
+
+        let (default, targets) = levels.list();
+        assert_eq!(default, "error");
+        assert_eq!(targets.get("api").unwrap(), "trace");
+        assert!(!targets.contains_key("job"));
+    }
+
+    #[test]
+    fn rotating_file() {
+        let dir = std::env::temp_dir().join(format!("rpa-log-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("job.log");
+        let mut file = RotatingFile::new(path.to_str().unwrap(), 10, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(fs::read_to_string(file.rotated(1)).unwrap(), "cccccc\n");
+        assert_eq!(fs::read_to_string(file.rotated(2)).unwrap(), "bbbbbb\n");
+        assert!(!file.rotated(3).exists());
+
+        // appends to the existing file
+        let file = RotatingFile::new(path.to_str().unwrap(), 10, 2).unwrap();
+        assert_eq!(file.size, 7);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,23 +1,24 @@
 use futures::future::FutureExt;
 use std::{io, net::SocketAddr, sync::Arc};
-use structured_logger::{async_json::new_writer, Builder};
 use tokio::{
     signal,
     time::{sleep, Duration},
 };
 
+mod audit;
 mod background_job;
 mod conf;
 mod http_api;
 mod jobs;
+mod logger;
+mod storage;
+mod telemetry;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> anyhow::Result<()> {
     let cfg = conf::Conf::new().unwrap_or_else(|err| panic!("config error: {}", err));
 
-    Builder::with_level(cfg.log.level.as_str())
-        .with_target_writer("*", new_writer(tokio::io::stdout()))
-        .init();
+    let log_levels = logger::init(&cfg.log).unwrap_or_else(|err| panic!("log error: {}", err));
 
     log::debug!("{:?}", cfg);
 
@@ -25,8 +26,9 @@ async fn main() -> anyhow::Result<()> {
     let server_env = cfg.env.clone();
     let addr = SocketAddr::from(([0, 0, 0, 0], server_cfg.port));
 
-    let app_state = cfg.new_app_state().await?;
+    let app_state = cfg.new_app_state(log_levels).await?;
     let app = http_api::new(app_state.clone()).await?;
+    app_state.leader.clone().spawn_renew();
     let shutdown = shutdown_signal(app_state.clone(), server_cfg.graceful_shutdown).shared();
 
     let api = async {
@@ -57,6 +59,8 @@ async fn main() -> anyhow::Result<()> {
     futures::future::try_join(api, monitor)
         .await
         .expect("Could not start services");
+    app_state.leader.release().await;
+    app_state.tracer.flush().await;
     Ok(())
 }
 
@@ -84,6 +88,8 @@ async fn shutdown_signal(app: Arc<conf::AppState>, wait_secs: usize) {
     }
 
     log::info!("signal received, starting graceful shutdown");
+    // running jobs stop, the items left are kept for the next run.
+    app.shutdown.cancel();
 
     let mut secs = wait_secs;
     loop {
@@ -0,0 +1,341 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum_web::object::{cbor_from_slice, cbor_to_vec, PackObject};
+
+use crate::conf;
+
+// Storage is a small key-value store for job state such as checkpoints,
+// dedup sets and retry counters.
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn set(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()>;
+
+    // returns false if the key does not exist.
+    fn delete(&self, key: &str) -> anyhow::Result<bool>;
+
+    // returns the keys with the prefix in order.
+    fn keys(&self, prefix: &str) -> anyhow::Result<Vec<String>>;
+
+    // adds delta to the counter and returns the new value, a missing counter is 0.
+    fn incr(&self, key: &str, delta: i64) -> anyhow::Result<i64>;
+}
+
+impl dyn Storage {
+    pub fn get_object<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        match self.get(key)? {
+            None => Ok(None),
+            Some(data) => Ok(Some(cbor_from_slice(&data)?)),
+        }
+    }
+
+    pub fn set_object<T: Serialize>(&self, key: &str, value: &T) -> anyhow::Result<()> {
+        self.set(key, cbor_to_vec(value)?)
+    }
+}
+
+pub fn new_storage(cfg: &conf::Storage) -> anyhow::Result<Arc<dyn Storage>> {
+    if cfg.path.is_empty() {
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+    Ok(Arc::new(FileStore::open(&cfg.path)?))
+}
+
+fn counter(value: Option<&Vec<u8>>) -> anyhow::Result<i64> {
+    match value {
+        None => Ok(0),
+        Some(data) => Ok(cbor_from_slice(data)?),
+    }
+}
+
+// MemoryStore keeps the data in memory only, it is lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl Storage for MemoryStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.data.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()> {
+        self.data.lock().unwrap().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.data.lock().unwrap().remove(key).is_some())
+    }
+
+    fn keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let data = self.data.lock().unwrap();
+        Ok(keys_with_prefix(&data, prefix))
+    }
+
+    fn incr(&self, key: &str, delta: i64) -> anyhow::Result<i64> {
+        let mut data = self.data.lock().unwrap();
+        let n = counter(data.get(key))? + delta;
+        data.insert(key.to_string(), cbor_to_vec(&n)?);
+        Ok(n)
+    }
+}
+
+fn keys_with_prefix(data: &BTreeMap<String, Vec<u8>>, prefix: &str) -> Vec<String> {
+    data.range(prefix.to_string()..)
+        .take_while(|(k, _)| k.starts_with(prefix))
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+// FileStore keeps the data in memory and appends every change to a log file of
+// CBOR records, (key, value) or (key, null) for a deletion. The log is compacted
+// when it holds twice as many records as keys: the data is written to a temporary
+// file that is synced and then renamed over the log. Appends are not synced, the
+// latest changes may be lost if the host crashes.
+pub struct FileStore {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    data: BTreeMap<String, Vec<u8>>,
+    log: File,
+    records: usize, // records in the log
+}
+
+// the log is not compacted below this many records.
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+impl FileStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let (data, records, clean) = load(&bytes)?;
+        if !clean {
+            log::warn!(target: "job",
+                action = "open_storage",
+                path = path.to_str().unwrap_or_default(),
+                keys = data.len();
+                "partly written tail, the file is rewritten",
+            );
+        }
+
+        let store = Self {
+            inner: Mutex::new(Inner {
+                data,
+                log: OpenOptions::new().create(true).append(true).open(&path)?,
+                records,
+            }),
+            path,
+        };
+        if !clean {
+            store.compact(&mut store.inner.lock().unwrap())?;
+        }
+        Ok(store)
+    }
+
+    // rewrites the log with the live data.
+    fn compact(&self, inner: &mut Inner) -> anyhow::Result<()> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for (key, value) in &inner.data {
+            ciborium::into_writer(&(key, Some(PackObject::Cbor(value.as_slice()))), &mut bytes)?;
+        }
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        inner.log = OpenOptions::new().append(true).open(&self.path)?;
+        inner.records = inner.data.len();
+        Ok(())
+    }
+
+    fn append(&self, inner: &mut Inner, key: &str) -> anyhow::Result<()> {
+        let value = inner.data.get(key).map(|v| PackObject::Cbor(v.as_slice()));
+        let mut bytes: Vec<u8> = Vec::new();
+        ciborium::into_writer(&(key, value), &mut bytes)?;
+        inner.log.write_all(&bytes)?;
+        inner.records += 1;
+        if inner.records > COMPACT_MIN_RECORDS.max(inner.data.len() * 2) {
+            if let Err(err) = self.compact(inner) {
+                // the log is still valid, it is compacted on a later change.
+                log::warn!(target: "job",
+                    action = "compact_storage",
+                    records = inner.records,
+                    error = err.to_string();
+                    "",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // applies the change and appends it to the log, the change is rolled back if it
+    // can not be appended.
+    fn update<R>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut BTreeMap<String, Vec<u8>>) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        let mut inner = self.inner.lock().unwrap();
+        let prev = inner.data.get(key).cloned();
+        let res = f(&mut inner.data)?;
+        if let Err(err) = self.append(&mut inner, key) {
+            match prev {
+                Some(v) => inner.data.insert(key.to_string(), v),
+                None => inner.data.remove(key),
+            };
+            // drops a partly written record.
+            let _ = self.compact(&mut inner);
+            return Err(err);
+        }
+        Ok(res)
+    }
+}
+
+// replays the log, returns the data, the number of records and false if the file
+// ends with a partly written record and has to be rewritten.
+fn load(bytes: &[u8]) -> anyhow::Result<(BTreeMap<String, Vec<u8>>, usize, bool)> {
+    let mut data = BTreeMap::new();
+    let mut records = 0;
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        match ciborium::from_reader::<(String, Option<PackObject<Vec<u8>>>), _>(&mut rest) {
+            Ok((key, Some(value))) => {
+                data.insert(key, value.unwrap());
+            }
+            Ok((key, None)) => {
+                data.remove(&key);
+            }
+            Err(ciborium::de::Error::Io(_)) => return Ok((data, records, false)),
+            Err(err) => anyhow::bail!("corrupt storage file at record {}: {:?}", records, err),
+        }
+        records += 1;
+    }
+    Ok((data, records, true))
+}
+
+impl Storage for FileStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.inner.lock().unwrap().data.get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()> {
+        self.update(key, |data| {
+            data.insert(key.to_string(), value);
+            Ok(())
+        })
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        if !self.inner.lock().unwrap().data.contains_key(key) {
+            return Ok(false);
+        }
+        self.update(key, |data| Ok(data.remove(key).is_some()))
+    }
+
+    fn keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(keys_with_prefix(&inner.data, prefix))
+    }
+
+    fn incr(&self, key: &str, delta: i64) -> anyhow::Result<i64> {
+        self.update(key, |data| {
+            let n = counter(data.get(key))? + delta;
+            data.insert(key.to_string(), cbor_to_vec(&n)?);
+            Ok(n)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store() {
+        let dir = std::env::temp_dir().join(format!("rpa-storage-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("storage.cbor");
+        let path = path.to_str().unwrap();
+        {
+            let store: Arc<dyn Storage> = Arc::new(FileStore::open(path).unwrap());
+            assert_eq!(store.get("a").unwrap(), None);
+            store.set("a", vec![1, 2, 3]).unwrap();
+            store.set_object("review:cursor", &"next").unwrap();
+            assert_eq!(store.incr("review:failures:1", 1).unwrap(), 1);
+            assert_eq!(store.incr("review:failures:1", 2).unwrap(), 3);
+            assert_eq!(store.incr("review:failures:2", 1).unwrap(), 1);
+            assert!(store.delete("review:failures:2").unwrap());
+            assert!(!store.delete("review:failures:2").unwrap());
+        }
+
+        // reopen
+        let store: Arc<dyn Storage> = Arc::new(FileStore::open(path).unwrap());
+        assert_eq!(store.get("a").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(
+            store.get_object::<String>("review:cursor").unwrap(),
+            Some("next".to_string())
+        );
+        assert_eq!(store.incr("review:failures:1", 0).unwrap(), 3);
+        assert_eq!(
+            store.keys("review:").unwrap(),
+            vec!["review:cursor".to_string(), "review:failures:1".to_string()]
+        );
+        assert!(store.keys("x").unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_log() {
+        let dir = std::env::temp_dir().join(format!("rpa-storage-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("storage.cbor");
+        let path_str = path.to_str().unwrap();
+        {
+            let store: Arc<dyn Storage> = Arc::new(FileStore::open(path_str).unwrap());
+            for _ in 0..COMPACT_MIN_RECORDS * 3 {
+                store.incr("outbox:seq", 1).unwrap();
+            }
+            store.set("a", vec![1]).unwrap();
+        }
+        // compacted
+        assert!(fs::metadata(&path).unwrap().len() < 1000);
+
+        // a partly written record is dropped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0x82, 0x61]).unwrap();
+        let store: Arc<dyn Storage> = Arc::new(FileStore::open(path_str).unwrap());
+        assert_eq!(
+            store.incr("outbox:seq", 0).unwrap(),
+            COMPACT_MIN_RECORDS as i64 * 3
+        );
+        assert!(store.delete("a").unwrap());
+        let store: Arc<dyn Storage> = Arc::new(FileStore::open(path_str).unwrap());
+        assert_eq!(store.get("a").unwrap(), None);
+
+        // an unreadable file is corrupt
+        fs::write(&path, cbor_to_vec(&BTreeMap::from([("b", 2)])).unwrap()).unwrap();
+        assert!(FileStore::open(path_str).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum_web::context::{ReqContext, TraceContext};
+
+use crate::conf;
+
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttrValue {
+    fn from(v: &str) -> Self {
+        AttrValue::Str(v.to_string())
+    }
+}
+
+impl From<String> for AttrValue {
+    fn from(v: String) -> Self {
+        AttrValue::Str(v)
+    }
+}
+
+impl From<&String> for AttrValue {
+    fn from(v: &String) -> Self {
+        AttrValue::Str(v.clone())
+    }
+}
+
+impl From<i64> for AttrValue {
+    fn from(v: i64) -> Self {
+        AttrValue::Int(v)
+    }
+}
+
+impl From<u64> for AttrValue {
+    fn from(v: u64) -> Self {
+        AttrValue::Int(v as i64)
+    }
+}
+
+impl From<u16> for AttrValue {
+    fn from(v: u16) -> Self {
+        AttrValue::Int(v as i64)
+    }
+}
+
+impl From<bool> for AttrValue {
+    fn from(v: bool) -> Self {
+        AttrValue::Bool(v)
+    }
+}
+
+// Span is a finished or running operation of a trace, its attributes follow the
+// fields of the logs: rid, action and elapsed (added when finished).
+#[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub name: String,
+    pub kind: SpanKind,
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_id: String,
+    pub sampled: bool,
+    pub start_unix_nano: u64,
+    pub end_unix_nano: u64,
+    pub attributes: Vec<(String, AttrValue)>,
+    pub error: Option<String>,
+    start: Instant,
+}
+
+impl Span {
+    // starts a span for the current span of the trace context.
+    pub fn new(name: &str, kind: SpanKind, trace: &TraceContext) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            trace_id: trace.trace_id.clone(),
+            span_id: trace.span_id.clone(),
+            parent_id: trace.parent_id.clone(),
+            sampled: trace.flags & 1 == 1,
+            start_unix_nano: unix_nano(),
+            end_unix_nano: 0,
+            attributes: Vec::new(),
+            error: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn attr(&mut self, key: &str, value: impl Into<AttrValue>) -> &mut Self {
+        self.attributes.push((key.to_string(), value.into()));
+        self
+    }
+
+    pub fn error(&mut self, err: impl ToString) -> &mut Self {
+        self.error = Some(err.to_string());
+        self
+    }
+
+    fn end(&mut self) {
+        self.end_unix_nano = unix_nano();
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.attr("elapsed", elapsed);
+    }
+}
+
+fn unix_nano() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+// Tracer collects finished spans and exports them when the "otlp" feature is
+// enabled and an endpoint is configured, otherwise spans are dropped.
+pub struct Tracer {
+    #[cfg(feature = "otlp")]
+    exporter: Option<Arc<otlp::Exporter>>,
+}
+
+impl Tracer {
+    pub fn new(cfg: &conf::Otlp) -> anyhow::Result<Self> {
+        #[cfg(feature = "otlp")]
+        {
+            if cfg.endpoint.is_empty() {
+                return Ok(Self { exporter: None });
+            }
+            let exporter = Arc::new(otlp::Exporter::new(cfg)?);
+            otlp::spawn_flush(exporter.clone(), cfg.flush_interval);
+            Ok(Self {
+                exporter: Some(exporter),
+            })
+        }
+
+        #[cfg(not(feature = "otlp"))]
+        {
+            if !cfg.endpoint.is_empty() {
+                log::warn!(target: "otlp",
+                    endpoint = &cfg.endpoint;
+                    "the \"otlp\" feature is disabled, spans are not exported",
+                );
+            }
+            Ok(Self {})
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        #[cfg(feature = "otlp")]
+        {
+            self.exporter.is_some()
+        }
+
+        #[cfg(not(feature = "otlp"))]
+        {
+            false
+        }
+    }
+
+    pub fn finish(&self, mut span: Span) {
+        if !self.enabled() || !span.sampled {
+            return;
+        }
+
+        span.end();
+        #[cfg(feature = "otlp")]
+        if let Some(exporter) = &self.exporter {
+            exporter.push(span);
+        }
+    }
+
+    // exports the pending spans, called on shutdown.
+    pub async fn flush(&self) {
+        #[cfg(feature = "otlp")]
+        if let Some(exporter) = &self.exporter {
+            if let Err(err) = exporter.flush().await {
+                log::warn!(target: "otlp",
+                    error = err.to_string();
+                    "flush failed",
+                );
+            }
+        }
+    }
+}
+
+// middleware records a server span for every request, it must run inside
+// axum_web::context::middleware that puts the ReqContext into the extensions.
+pub async fn middleware<B>(
+    State(app): State<Arc<conf::AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ctx = match req.extensions().get::<Arc<ReqContext>>() {
+        Some(ctx) if app.tracer.enabled() => ctx.clone(),
+        _ => return next.run(req).await,
+    };
+
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let mut span = Span::new(
+        &format!("{} {}", method, path),
+        SpanKind::Server,
+        &ctx.trace,
+    );
+    let res = next.run(req).await;
+    let status = res.status().as_u16();
+    span.attr("rid", &ctx.rid)
+        .attr("action", "api")
+        .attr("http.method", method)
+        .attr("http.target", path)
+        .attr("http.status_code", status);
+    if status >= 500 {
+        span.error(format!("status {}", status));
+    }
+    app.tracer.finish(span);
+    res
+}
@@ -0,0 +1,235 @@
+use reqwest::{header, Client};
+use serde_json::{json, Value};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use super::{AttrValue, Span};
+use crate::conf;
+
+// Exporter sends spans to an OTLP/HTTP collector with the JSON encoding,
+// see https://opentelemetry.io/docs/specs/otlp/#otlphttp.
+pub struct Exporter {
+    client: Client,
+    url: reqwest::Url,
+    service_name: String,
+    batch_size: usize,
+    max_queue: usize,
+    queue: Mutex<Vec<Span>>,
+    dropped: AtomicU64,
+}
+
+impl Exporter {
+    pub fn new(cfg: &conf::Otlp) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(&cfg.endpoint)?.join("/v1/traces")?;
+        let client = Client::builder()
+            .https_only(false)
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(cfg.timeout))
+            .build()?;
+        Ok(Self {
+            client,
+            url,
+            service_name: cfg.service_name.clone(),
+            batch_size: cfg.batch_size.max(1),
+            max_queue: cfg.max_queue.max(1),
+            queue: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn push(&self, span: Span) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.max_queue {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push(span);
+    }
+
+    // exports all queued spans in batches, returns the number of exported spans.
+    pub async fn flush(&self) -> anyhow::Result<usize> {
+        let mut total = 0;
+        loop {
+            let batch: Vec<Span> = {
+                let mut queue = self.queue.lock().unwrap();
+                let n = queue.len().min(self.batch_size);
+                queue.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                break;
+            }
+
+            let res = self
+                .client
+                .post(self.url.clone())
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&self.encode(&batch))?)
+                .send()
+                .await?;
+            let status = res.status().as_u16();
+            if status >= 300 {
+                let text = res.text().await.unwrap_or_default();
+                anyhow::bail!(
+                    "{} spans not exported, status {}: {}",
+                    batch.len(),
+                    status,
+                    text
+                );
+            }
+            total += batch.len();
+        }
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!(target: "otlp",
+                dropped = dropped;
+                "queue is full, spans dropped",
+            );
+        }
+        Ok(total)
+    }
+
+    fn encode(&self, spans: &[Span]) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let mut v = json!({
+                    "traceId": span.trace_id,
+                    "spanId": span.span_id,
+                    "name": span.name,
+                    "kind": span.kind as u8,
+                    "startTimeUnixNano": span.start_unix_nano.to_string(),
+                    "endTimeUnixNano": span.end_unix_nano.to_string(),
+                    "attributes": span
+                        .attributes
+                        .iter()
+                        .map(|(k, v)| attribute(k, v))
+                        .collect::<Vec<Value>>(),
+                    "status": match &span.error {
+                        None => json!({"code": 1}),
+                        Some(msg) => json!({"code": 2, "message": msg}),
+                    },
+                });
+                if !span.parent_id.is_empty() {
+                    v["parentSpanId"] = json!(span.parent_id);
+                }
+                v
+            })
+            .collect();
+
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", &AttrValue::Str(self.service_name.clone()))],
+                },
+                "scopeSpans": [{
+                    "scope": {
+                        "name": conf::APP_NAME,
+                        "version": conf::APP_VERSION,
+                    },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+}
+
+fn attribute(key: &str, value: &AttrValue) -> Value {
+    let value = match value {
+        AttrValue::Str(v) => json!({ "stringValue": v }),
+        // int64 is a string in the JSON encoding
+        AttrValue::Int(v) => json!({ "intValue": v.to_string() }),
+        AttrValue::Bool(v) => json!({ "boolValue": v }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+pub fn spawn_flush(exporter: Arc<Exporter>, interval: u64) {
+    let interval = Duration::from_secs(interval.max(1));
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(err) = exporter.flush().await {
+                log::warn!(target: "otlp",
+                    error = err.to_string();
+                    "export failed",
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jobs::testing::serve, telemetry::SpanKind};
+    use axum::{extract::State, routing, Json, Router};
+    use axum_web::context::TraceContext;
+
+    type Received = Arc<Mutex<Vec<Value>>>;
+
+    async fn collect(State(received): State<Received>, Json(body): Json<Value>) -> Json<Value> {
+        received.lock().unwrap().push(body);
+        Json(json!({}))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_spans() {
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/v1/traces", routing::post(collect))
+            .with_state(received.clone());
+        let addr = serve(app);
+
+        let exporter = Exporter::new(&conf::Otlp {
+            endpoint: format!("http://{}", addr),
+            batch_size: 2,
+            max_queue: 3,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let trace = TraceContext::new();
+        for i in 0..4 {
+            let trace = if i == 0 { trace.clone() } else { trace.child() };
+            let mut span = Span::new("publication_review", SpanKind::Internal, &trace);
+            span.attr("rid", "rid").attr("action", "publication_review");
+            if i == 1 {
+                span.error("failed");
+            }
+            span.end();
+            exporter.push(span);
+        }
+        // the fourth span is dropped
+        assert_eq!(exporter.flush().await.unwrap(), 3);
+        assert_eq!(exporter.flush().await.unwrap(), 0);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let rs = &received[0]["resourceSpans"][0];
+        assert_eq!(
+            rs["resource"]["attributes"][0]["value"]["stringValue"],
+            "yiwen-rpa"
+        );
+        let spans = rs["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["traceId"], trace.trace_id);
+        assert_eq!(spans[0]["spanId"], trace.span_id);
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!(spans[0]["kind"], 1);
+        assert_eq!(spans[0]["status"]["code"], 1);
+        assert_eq!(spans[1]["parentSpanId"], trace.span_id);
+        assert_eq!(spans[1]["status"]["code"], 2);
+        assert_eq!(spans[1]["status"]["message"], "failed");
+
+        let attrs = spans[0]["attributes"].as_array().unwrap();
+        assert_eq!(attrs[0]["key"], "rid");
+        assert_eq!(attrs[2]["key"], "elapsed");
+        assert!(attrs[2]["value"]["intValue"].is_string());
+    }
+}